@@ -0,0 +1,30 @@
+//! Compare the tree-walking interpreter against the bytecode interpreter on mandelbrot.
+//!
+//! Run with `cargo bench --bench interpreters`.
+
+use std::{io, time::Duration};
+
+use bf::ir::{parse_bytes, BrainfuckNode};
+use bf::opt::PassManager;
+use bf::vm::{VMInterface, VM, VMBytecode};
+
+fn bench<V: VMInterface>(name: &str, ir: Vec<BrainfuckNode>) -> Duration {
+    let mut vm = V::new(ir, Box::new(io::empty()), Box::new(io::sink())).expect("interpreter should start");
+    let duration = vm.run().expect("benchmark program should run");
+    println!("{:<24} {:>12?}", name, duration);
+    duration
+}
+
+fn main() {
+    let mandelbrot = concat!(env!("CARGO_MANIFEST_DIR"), "/example/mandelbrot.bf");
+    let src = std::fs::read(mandelbrot).expect("example should exist");
+    for level in [0, 3] {
+        let mut ir = parse_bytes(&src, false).expect("benchmark input should parse");
+        PassManager::with_level(level).run(&mut ir);
+
+        println!("mandelbrot.bf -O{}:", level);
+        let tree = bench::<VM>("  tree walker", ir.clone());
+        let bytecode = bench::<VMBytecode>("  bytecode", ir);
+        println!("  speedup {:.1}x", tree.as_secs_f64() / bytecode.as_secs_f64());
+    }
+}
@@ -0,0 +1,51 @@
+//! Compare the hand-written parser against the peg grammar.
+//!
+//! Run with `cargo bench --bench parser`.
+
+use std::time::Duration;
+
+use bf::ir::{grammar::parse_peg, parse_bytes, BrainfuckNode, ParseError};
+
+const ITERATIONS: u32 = 20;
+const NESTING_DEPTH: usize = 20_000;
+// the peg grammar recurses once per nested `[`, give it room to finish
+const STACK_SIZE: usize = 1 << 30;
+
+type ParseFn = fn(&[u8], bool) -> Result<Vec<BrainfuckNode>, ParseError>;
+
+fn bench(name: &str, src: &[u8], parse: ParseFn) -> Duration {
+    let clock = quanta::Clock::new();
+    let mut total = Duration::ZERO;
+    for _ in 0..ITERATIONS {
+        let start = clock.now();
+        let ir = parse(src, false).expect("benchmark input should parse");
+        total += clock.now() - start;
+        drop(ir);
+    }
+    let average = total / ITERATIONS;
+    println!("{:<24} {:>12?}", name, average);
+    average
+}
+
+fn compare(name: &str, src: Vec<u8>) {
+    println!("{} ({} bytes):", name, src.len());
+    let peg = bench("  peg grammar", &src, parse_peg);
+    let hand = bench("  hand-written parser", &src, parse_bytes);
+    println!("  speedup {:.1}x", peg.as_secs_f64() / hand.as_secs_f64());
+}
+
+fn main() {
+    let handle = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(|| {
+            let mandelbrot = concat!(env!("CARGO_MANIFEST_DIR"), "/example/mandelbrot.bf");
+            compare("mandelbrot.bf", std::fs::read(mandelbrot).expect("example should exist"));
+
+            let mut nested = "[".repeat(NESTING_DEPTH);
+            nested.push_str("+>-<");
+            nested.push_str(&"]".repeat(NESTING_DEPTH));
+            compare(&format!("nested {}", NESTING_DEPTH), nested.into_bytes());
+        })
+        .expect("could not spawn benchmark thread");
+    handle.join().expect("benchmark thread panicked");
+}
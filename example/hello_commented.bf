@@ -0,0 +1,10 @@
+#!/usr/bin/env bf
+# Prints "Hello World!" followed by a newline
+# Cell 0 counts down while cells 1 to 4 are primed with 70 and 100 and 30 and 10
+++++++++++[>+++++++>++++++++++>+++>+<<<<-]
+# H e l l o
+>++.>+.+++++++..+++.
+# space then W o r l d !
+>++.<<+++++++++++++++.>.+++.------.--------.>+.
+# newline
+>.
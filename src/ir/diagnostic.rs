@@ -0,0 +1,157 @@
+use std::fmt::{self, Write};
+use thiserror::Error;
+
+use crate::ir::{LineIndex, Position};
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum BracketMismatch {
+    /// A `[` that is never closed
+    Unmatched(Position),
+    /// A `]` without an opening `[`
+    Stray(Position),
+}
+
+/// Every bracket mismatch found in a source file, in source order.
+#[derive(Debug, Clone, Eq, PartialEq, Error)]
+pub struct BracketError {
+    pub mismatches: Vec<BracketMismatch>,
+}
+
+impl BracketMismatch {
+    pub fn position(&self) -> Position {
+        match self {
+            BracketMismatch::Unmatched(pos) | BracketMismatch::Stray(pos) => *pos,
+        }
+    }
+}
+
+impl fmt::Display for BracketMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BracketMismatch::Unmatched(pos) => write!(
+                f,
+                "unmatched `[` opened at line {}, column {}",
+                pos.line, pos.column
+            ),
+            BracketMismatch::Stray(pos) => write!(
+                f,
+                "stray `]` at line {}, column {}",
+                pos.line, pos.column
+            ),
+        }
+    }
+}
+
+impl fmt::Display for BracketError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mismatches.as_slice() {
+            [mismatch] => write!(f, "{}", mismatch),
+            mismatches => write!(f, "{} mismatched brackets", mismatches.len()),
+        }
+    }
+}
+
+impl BracketError {
+    /// Render every mismatch with the offending source line and a caret under the bracket.
+    pub fn render(&self, file_name: &str, src: &[u8]) -> String {
+        let mut out = String::new();
+        for mismatch in &self.mismatches {
+            let pos = mismatch.position();
+            let line_start = src[..pos.offset].iter().rposition(|&c| c == b'\n').map_or(0, |i| i + 1);
+            let line = src[line_start..].split(|&c| c == b'\n').next().unwrap_or_default();
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            // keep tabs so the caret lines up with the source line
+            let indent: String = String::from_utf8_lossy(&src[line_start..pos.offset])
+                .chars()
+                .map(|c| if c == '\t' { '\t' } else { ' ' })
+                .collect();
+            let gutter = " ".repeat(pos.line.to_string().len());
+
+            let _ = writeln!(out, "error: {}", mismatch);
+            let _ = writeln!(out, "{}--> {}:{}:{}", gutter, file_name, pos.line, pos.column);
+            let _ = writeln!(out, "{} |", gutter);
+            let _ = writeln!(out, "{} | {}", pos.line, String::from_utf8_lossy(line));
+            let _ = writeln!(out, "{} | {}^", gutter, indent);
+            let _ = writeln!(out);
+        }
+        if self.mismatches.len() > 1 {
+            let _ = writeln!(out, "error: {}", self);
+        }
+        out
+    }
+}
+
+/// Match every bracket in `src` and collect all mismatches instead of stopping at the first.
+pub fn check_brackets(src: &[u8], lines: &LineIndex) -> Result<(), BracketError> {
+    let mut open = Vec::new();
+    let mut mismatches = Vec::new();
+    for (offset, c) in src.iter().enumerate() {
+        if *c == b'[' {
+            open.push(offset);
+        } else if *c == b']' && open.pop().is_none() {
+            mismatches.push(BracketMismatch::Stray(lines.position(offset)));
+        }
+    }
+    mismatches.extend(
+        open.into_iter()
+            .map(|offset| BracketMismatch::Unmatched(lines.position(offset))),
+    );
+
+    if mismatches.is_empty() {
+        return Ok(());
+    }
+    mismatches.sort_by_key(|mismatch| mismatch.position().offset);
+    Err(BracketError { mismatches })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ir::{parse_bytes, ParseError};
+
+    fn mismatches(src: &[u8]) -> BracketError {
+        match parse_bytes(src, false) {
+            Err(ParseError::Brackets(err)) => err,
+            result => panic!("expected mismatched brackets, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn reports_every_mismatch() {
+        let src = b"[+\n]] stray\n[";
+        let err = mismatches(src);
+        let positions: Vec<_> = err.mismatches.iter().map(|m| (m.position().line, m.position().column)).collect();
+        assert_eq!(positions, [(2, 2), (3, 1)]);
+        assert_eq!(err.to_string(), "2 mismatched brackets");
+        assert_eq!(
+            err.render("test.bf", src),
+            concat!(
+                "error: stray `]` at line 2, column 2\n",
+                " --> test.bf:2:2\n",
+                "  |\n",
+                "2 | ]] stray\n",
+                "  |  ^\n",
+                "\n",
+                "error: unmatched `[` opened at line 3, column 1\n",
+                " --> test.bf:3:1\n",
+                "  |\n",
+                "3 | [\n",
+                "  | ^\n",
+                "\n",
+                "error: 2 mismatched brackets\n",
+            )
+        );
+    }
+
+    #[test]
+    fn columns_count_characters() {
+        // `é` is two bytes in UTF-8 and one in Latin-1, the caret keeps the tab
+        for src in ["\tcafé ]".as_bytes(), b"\tcaf\xe9 ]"] {
+            let err = mismatches(src);
+            assert_eq!(err.to_string(), "stray `]` at line 1, column 7");
+            let rendered = err.render("test.bf", src);
+            let caret = rendered.lines().nth(4).unwrap();
+            assert_eq!(caret, "  | \t     ^");
+        }
+    }
+}
@@ -0,0 +1,103 @@
+use peg::error::ParseError as PegError;
+
+use crate::ir::{diagnostic, is_command, BrainfuckIR, BrainfuckNode, LineIndex, ParseError, Position, Span};
+
+/// Parse with the peg grammar.
+///
+/// Kept as the reference implementation for `parse_bytes`, the grammar recurses for every
+/// nested `[` so deeply nested sources can overflow the stack.
+pub fn parse_peg(src: &[u8], strict: bool) -> Result<Vec<BrainfuckNode>, ParseError> {
+    let lines = LineIndex::new(src);
+    diagnostic::check_brackets(src, &lines)?;
+
+    let mut ir = brainfuck_parser::compile_peg(src, strict)
+        .map_err(|err: PegError<usize>| ParseError::Syntax {
+            position: lines.position(err.location),
+            found: src[err.location],
+        })?;
+    locate(&mut ir, &lines);
+    Ok(ir)
+}
+
+impl Span {
+    /// A span holding only byte offsets, lines and columns are filled in by `locate`.
+    fn from_offsets(start: usize, end: usize) -> Self {
+        Self {
+            start: Position { offset: start, ..Default::default() },
+            end: Position { offset: end, ..Default::default() },
+        }
+    }
+}
+
+fn locate(block: &mut [BrainfuckNode], lines: &LineIndex) {
+    for node in block {
+        node.span.start = lines.position(node.span.start.offset);
+        node.span.end = lines.position(node.span.end.offset);
+        if let BrainfuckIR::Loop(body) = &mut node.ir {
+            locate(body, lines);
+        }
+    }
+}
+
+peg::parser!(pub grammar brainfuck_parser(strict: bool) for [u8] {
+    pub rule compile_peg() -> Vec<BrainfuckNode>
+        = skip()* inst:instruction_with_skip()* skip()* { inst }
+
+    rule instruction_with_skip() -> BrainfuckNode
+        = skip()* inst:spanned(<instruction()>) skip()* { inst }
+
+    rule spanned(inst: rule<BrainfuckIR>) -> BrainfuckNode
+        = start:position!() ir:inst() end:position!() {
+            BrainfuckNode::new(ir, Span::from_offsets(start, end))
+        }
+
+    rule instruction() -> BrainfuckIR
+        = add_val()
+        / sub_val()
+        / ptr_right()
+        / ptr_left()
+        / put_byte()
+        / get_byte()
+        / r#loop()
+
+    rule add_val() -> BrainfuckIR
+        = n:"+"+ {
+            BrainfuckIR::AddVal(n.len() as u32, 0)
+        }
+
+    rule sub_val() -> BrainfuckIR
+        = n:"-"+ {
+            BrainfuckIR::SubVal(n.len() as u32, 0)
+        }
+
+    rule ptr_right() -> BrainfuckIR
+        = n:">"+ {
+            BrainfuckIR::PtrMovRight(n.len() as u32)
+        }
+
+    rule ptr_left() -> BrainfuckIR
+        = n:"<"+ {
+            BrainfuckIR::PtrMovLeft(n.len() as u32)
+        }
+
+    rule put_byte() -> BrainfuckIR
+        = "." {
+            BrainfuckIR::PutByte(0)
+        }
+
+    rule get_byte() -> BrainfuckIR
+        = "," {
+            BrainfuckIR::GetByte(0)
+        }
+
+    rule r#loop() -> BrainfuckIR
+        = "[" skip()* loop_block:instruction_with_skip()* "]" {
+            BrainfuckIR::Loop(loop_block)
+        }
+
+    // in strict mode only whitespace is allowed between instructions,
+    // otherwise every non-command character is treated as a comment
+    rule skip()
+        = quiet!{[c if c == b' ' || c == b'\n' || c == b'\t' || (!strict && !is_command(c))]}
+        / expected!("whitespace")
+});
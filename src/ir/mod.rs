@@ -0,0 +1,293 @@
+mod diagnostic;
+mod parser;
+pub mod grammar;
+
+use std::fmt;
+use thiserror::Error;
+
+pub use diagnostic::{BracketError, BracketMismatch};
+
+/// Instructions that access memory carry the offset of their cell from the pointer,
+/// the parser always emits `0` and the optimizer folds pointer movement into it.
+///
+/// Constants are kept modulo 2^32 and truncated to the cell width by the backends,
+/// so the same IR is valid for every cell width.
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum BrainfuckIR {
+    AddVal(u32, i32),         // +
+    SubVal(u32, i32),         // -
+    PtrMovRight(u32),         // >
+    PtrMovLeft(u32),          // <
+    PutByte(i32),             // .
+    GetByte(i32),             // ,
+    Loop(Vec<BrainfuckNode>), // [ loop_block ]
+    SetVal(u32, i32),         // [-] followed by an optional +/- run
+    MulAdd {                  // [->+<] as mem[ptr + offset] += mem[ptr + src] * factor
+        src: i32,
+        offset: i32,
+        factor: u32,
+    },
+    ScanRight(u32),           // [>] moves right until a zero cell
+    ScanLeft(u32),            // [<] moves left until a zero cell
+    PutBytes(Vec<u8>),        // output computed at compile time, written in one call
+}
+
+/// An instruction together with the source range it was parsed from.
+#[derive(Debug, Eq, PartialEq)]
+pub struct BrainfuckNode {
+    pub ir: BrainfuckIR,
+    pub span: Span,
+}
+
+/// A location in the source file, `line` and `column` are 1-based.
+///
+/// `column` counts characters: every byte that doesn't continue a UTF-8 sequence starts one,
+/// so multi-byte characters in comments count once and other bytes count one each.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Position {
+    pub offset: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+/// A half-open source range `[start, end)`.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub struct Span {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl BrainfuckNode {
+    pub fn new(ir: BrainfuckIR, span: Span) -> Self {
+        Self { ir, span }
+    }
+}
+
+impl Span {
+    /// The smallest span covering both `self` and `other`.
+    pub fn merge(self, other: Span) -> Span {
+        let start = if self.start.offset <= other.start.offset { self.start } else { other.start };
+        let end = if self.end.offset >= other.end.offset { self.end } else { other.end };
+        Span { start, end }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.start, self.end)
+    }
+}
+
+impl fmt::Display for BrainfuckIR {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BrainfuckIR::AddVal(n, offset) => write!(f, "add [{:+}] {}", offset, n),
+            BrainfuckIR::SubVal(n, offset) => write!(f, "sub [{:+}] {}", offset, n),
+            BrainfuckIR::PtrMovRight(n) => write!(f, "right {}", n),
+            BrainfuckIR::PtrMovLeft(n) => write!(f, "left {}", n),
+            BrainfuckIR::PutByte(offset) => write!(f, "put [{:+}]", offset),
+            BrainfuckIR::GetByte(offset) => write!(f, "get [{:+}]", offset),
+            BrainfuckIR::Loop(_) => write!(f, "loop"),
+            BrainfuckIR::SetVal(n, offset) => write!(f, "set [{:+}] {}", offset, n),
+            BrainfuckIR::MulAdd { src, offset, factor } => {
+                write!(f, "muladd [{:+}] [{:+}] * {}", offset, src, factor)
+            }
+            BrainfuckIR::ScanRight(n) => write!(f, "scan right {}", n),
+            BrainfuckIR::ScanLeft(n) => write!(f, "scan left {}", n),
+            BrainfuckIR::PutBytes(bytes) => write!(f, "put bytes \"{}\"", bytes.escape_ascii()),
+        }
+    }
+}
+
+/// Render the IR tree one instruction per line, loop bodies are indented.
+pub fn print_ir(ir: &[BrainfuckNode]) -> String {
+    let mut out = String::new();
+    let mut depth = 0;
+    for step in walk(ir) {
+        match step {
+            Walk::Enter(node) => {
+                let inst = format!("{}{}", "  ".repeat(depth), node.ir);
+                out.push_str(&format!("{:<32} ; {}\n", inst, node.span));
+                if let BrainfuckIR::Loop(_) = node.ir {
+                    depth += 1;
+                }
+            }
+            Walk::Exit(_) => {
+                depth -= 1;
+                out.push_str(&format!("{}end\n", "  ".repeat(depth)));
+            }
+        }
+    }
+    out
+}
+
+/// A step of `walk`.
+pub(crate) enum Walk<'a> {
+    /// A node, loops are entered before their body
+    Enter(&'a BrainfuckNode),
+    /// The loop whose body just ended
+    Exit(&'a BrainfuckNode),
+}
+
+/// Every node of the tree in program order, each loop followed by its body and its `Exit`.
+///
+/// Loops can be nested arbitrarily deep, so code walking the tree keeps its own stack
+/// instead of recursing.
+pub(crate) fn walk(ir: &[BrainfuckNode]) -> impl Iterator<Item = Walk<'_>> {
+    // the blocks being walked, innermost last, with the loop each one is the body of
+    let mut blocks = vec![(ir.iter(), None)];
+    std::iter::from_fn(move || {
+        let (nodes, _) = blocks.last_mut()?;
+        match nodes.next() {
+            Some(node) => {
+                if let BrainfuckIR::Loop(body) = &node.ir {
+                    blocks.push((body.iter(), Some(node)));
+                }
+                Some(Walk::Enter(node))
+            }
+            // the end of the program has no loop, which ends the walk
+            None => blocks.pop()?.1.map(Walk::Exit),
+        }
+    })
+}
+
+/// Call `f` on every block of the tree, loop bodies before the block they are in.
+///
+/// Bodies are taken out of their loop while their block waits on them, so `f` owns the
+/// block it is given and the walk doesn't recurse.
+pub(crate) fn for_each_block_mut(ir: &mut Vec<BrainfuckNode>, mut f: impl FnMut(&mut Vec<BrainfuckNode>)) {
+    // the blocks being walked, innermost last, with the index of their next node
+    let mut blocks = vec![(std::mem::take(ir), 0)];
+    while let Some((block, next)) = blocks.last_mut() {
+        if let Some(node) = block.get_mut(*next) {
+            *next += 1;
+            if let BrainfuckIR::Loop(body) = &mut node.ir {
+                let body = std::mem::take(body);
+                blocks.push((body, 0));
+            }
+            continue;
+        }
+
+        let (mut block, _) = blocks.pop().unwrap();
+        f(&mut block);
+        match blocks.last_mut() {
+            // the loop is the node before the next one of its block
+            Some((parent, next)) => {
+                if let BrainfuckIR::Loop(body) = &mut parent[*next - 1].ir {
+                    *body = block;
+                }
+            }
+            None => *ir = block,
+        }
+    }
+}
+
+impl Clone for BrainfuckNode {
+    /// Copy nested loops one at a time, like `drop`.
+    fn clone(&self) -> Self {
+        // the copies of the blocks being walked, innermost last
+        let mut blocks = vec![Vec::new()];
+        for step in walk(std::slice::from_ref(self)) {
+            match step {
+                Walk::Enter(BrainfuckNode { ir: BrainfuckIR::Loop(_), .. }) => blocks.push(Vec::new()),
+                Walk::Enter(node) => {
+                    let copy = BrainfuckNode::new(node.ir.clone(), node.span);
+                    blocks.last_mut().expect("the outermost block is never popped").push(copy);
+                }
+                Walk::Exit(node) => {
+                    let body = blocks.pop().expect("every loop is entered before it ends");
+                    let copy = BrainfuckNode::new(BrainfuckIR::Loop(body), node.span);
+                    blocks.last_mut().expect("the outermost block is never popped").push(copy);
+                }
+            }
+        }
+        blocks.pop().and_then(|mut block| block.pop()).expect("the walk copies `self`")
+    }
+}
+
+impl Drop for BrainfuckNode {
+    /// Drop nested loops one at a time, the derived drop would recurse once per level.
+    fn drop(&mut self) {
+        let BrainfuckIR::Loop(body) = &mut self.ir else {
+            return;
+        };
+        let mut nodes = std::mem::take(body);
+        while let Some(mut node) = nodes.pop() {
+            if let BrainfuckIR::Loop(body) = &mut node.ir {
+                nodes.append(body);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ParseError {
+    #[error(transparent)]
+    Brackets(#[from] BracketError),
+    #[error(
+        "syntax error at {position}: unexpected `{}`, only whitespace is allowed between instructions in strict mode",
+        std::ascii::escape_default(*found)
+    )]
+    Syntax {
+        position: Position,
+        found: u8,
+    },
+}
+
+/// Parse a brainfuck program, every node carries its source span.
+pub fn parse(src: &str, strict: bool) -> Result<Vec<BrainfuckNode>, ParseError> {
+    parse_bytes(src.as_bytes(), strict)
+}
+
+/// Parse a brainfuck program from raw bytes, the source doesn't need to be valid UTF-8.
+pub fn parse_bytes(src: &[u8], strict: bool) -> Result<Vec<BrainfuckNode>, ParseError> {
+    parser::parse(src, strict)
+}
+
+/// Turns byte offsets into positions, for code that only keeps offsets.
+pub(crate) struct LineIndex {
+    /// offset of the first byte of every line
+    line_starts: Vec<usize>,
+    /// number of characters started before every offset
+    chars: Vec<usize>,
+}
+
+impl LineIndex {
+    pub(crate) fn new(src: &[u8]) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(src.iter().enumerate().filter(|(_, &c)| c == b'\n').map(|(i, _)| i + 1))
+            .collect();
+        let chars = std::iter::once(0)
+            .chain(src.iter().scan(0, |chars, &c| {
+                *chars += usize::from(starts_char(c));
+                Some(*chars)
+            }))
+            .collect();
+        Self { line_starts, chars }
+    }
+
+    pub(crate) fn position(&self, offset: usize) -> Position {
+        // index of the last line starting at or before offset
+        let line = self.line_starts.partition_point(|&start| start <= offset) - 1;
+        Position {
+            offset,
+            line: line + 1,
+            column: self.chars[offset] - self.chars[self.line_starts[line]] + 1,
+        }
+    }
+}
+
+/// Whether `c` starts a character rather than continuing a UTF-8 sequence.
+pub(crate) fn starts_char(c: u8) -> bool {
+    c & 0xc0 != 0x80
+}
+
+pub(crate) fn is_command(c: u8) -> bool {
+    matches!(c, b'+' | b'-' | b'<' | b'>' | b'.' | b',' | b'[' | b']')
+}
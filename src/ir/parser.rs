@@ -0,0 +1,87 @@
+use crate::ir::{
+    is_command, starts_char, BracketError, BracketMismatch, BrainfuckIR, BrainfuckNode, ParseError,
+    Position, Span,
+};
+
+/// An open `[` waiting for its `]`.
+struct Frame {
+    start: Position,
+    block: Vec<BrainfuckNode>,
+}
+
+/// Single-pass parser with an explicit loop stack, so nesting depth is only bounded by memory.
+///
+/// Produces the same nodes as the peg grammar: runs of the same command are folded
+/// into one node, anything between two commands starts a new run.
+pub fn parse(src: &[u8], strict: bool) -> Result<Vec<BrainfuckNode>, ParseError> {
+    let mut stack: Vec<Frame> = Vec::new();
+    let mut block = Vec::new();
+    let mut mismatches = Vec::new();
+    let mut syntax_error = None;
+
+    let mut pos = Position { offset: 0, line: 1, column: 1 };
+    while pos.offset < src.len() {
+        let c = src[pos.offset];
+        let start = pos;
+
+        // consume the run of identical commands starting here
+        let run = if matches!(c, b'+' | b'-' | b'<' | b'>') {
+            src[pos.offset..].iter().take_while(|&&next| next == c).count()
+        } else {
+            1
+        };
+        advance(&mut pos, c, run);
+
+        match c {
+            b'+' => block.push(node(BrainfuckIR::AddVal(run as u32, 0), start, pos)),
+            b'-' => block.push(node(BrainfuckIR::SubVal(run as u32, 0), start, pos)),
+            b'>' => block.push(node(BrainfuckIR::PtrMovRight(run as u32), start, pos)),
+            b'<' => block.push(node(BrainfuckIR::PtrMovLeft(run as u32), start, pos)),
+            b'.' => block.push(node(BrainfuckIR::PutByte(0), start, pos)),
+            b',' => block.push(node(BrainfuckIR::GetByte(0), start, pos)),
+            b'[' => {
+                stack.push(Frame { start, block: std::mem::take(&mut block) });
+            }
+            b']' => match stack.pop() {
+                Some(frame) => {
+                    let body = std::mem::replace(&mut block, frame.block);
+                    block.push(node(BrainfuckIR::Loop(body), frame.start, pos));
+                }
+                None => mismatches.push(BracketMismatch::Stray(start)),
+            },
+            b' ' | b'\n' | b'\t' => {}
+            _ => {
+                debug_assert!(!is_command(c));
+                if strict && syntax_error.is_none() {
+                    syntax_error = Some(ParseError::Syntax { position: start, found: c });
+                }
+            }
+        }
+    }
+
+    // every frame left on the stack is a `[` without its `]`
+    mismatches.extend(stack.iter().map(|frame| BracketMismatch::Unmatched(frame.start)));
+    if !mismatches.is_empty() {
+        mismatches.sort_by_key(|mismatch| mismatch.position().offset);
+        return Err(BracketError { mismatches }.into());
+    }
+    if let Some(err) = syntax_error {
+        return Err(err);
+    }
+
+    Ok(block)
+}
+
+fn node(ir: BrainfuckIR, start: Position, end: Position) -> BrainfuckNode {
+    BrainfuckNode::new(ir, Span { start, end })
+}
+
+fn advance(pos: &mut Position, c: u8, count: usize) {
+    pos.offset += count;
+    if c == b'\n' {
+        pos.line += 1;
+        pos.column = 1;
+    } else if starts_char(c) {
+        pos.column += count;
+    }
+}
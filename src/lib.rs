@@ -0,0 +1,3 @@
+pub mod ir;
+pub mod opt;
+pub mod vm;
@@ -1,20 +1,61 @@
-mod ir;
-mod vm;
-
 use std::{
     io::{stdin, stdout},
     path::PathBuf,
+    time::Duration,
 };
 
+use bf::ir::{self, ParseError};
+use bf::opt::PassManager;
+use bf::vm::{BoundsChecks, CellWidth, EofPolicy, RuntimeError, TapePolicy, VMConfig, VMInterface, VM, VMBytecode, VMCranelift, LLVM, MEMORY_SIZE};
 use clap::{Parser, Subcommand};
-use ir::brainfuck_parser::compile_peg;
-use vm::{VMInterface, VM, VMCranelift, LLVM};
+
+/// Exit status used when the source has mismatched brackets, clap exits with 2 on usage
+/// errors and a failed run with 1
+const BRACKET_MISMATCH_EXIT_CODE: i32 = 3;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
 struct Cli {
     #[clap(name = "FILE")]
     source_file: PathBuf,
+    /// Reject any character that is not a command or whitespace
+    #[clap(long, default_value_t = false)]
+    strict: bool,
+    /// Optimization level, from -O0 (no passes) to -O3
+    #[clap(short = 'O', long, default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=3))]
+    opt_level: u8,
+    /// Print the IR to stderr after every optimization pass
+    #[clap(long, default_value_t = false)]
+    print_ir_after_all: bool,
+    /// Print what each optimization pass changed to stderr
+    #[clap(long, default_value_t = false)]
+    print_pass_stats: bool,
+    /// Width of a tape cell in bits: 8, 16 or 32, output writes the low byte of a cell
+    #[clap(long, default_value_t = CellWidth::U8)]
+    cell_width: CellWidth,
+    /// Number of cells on the tape, the initial size of a growing tape
+    #[clap(long, default_value_t = MEMORY_SIZE)]
+    tape_size: usize,
+    /// What happens when the pointer leaves the tape: fixed (error), wrap or grow (interpreter only)
+    #[clap(long, default_value_t = TapePolicy::Fixed)]
+    tape_policy: TapePolicy,
+    /// Extend the tape to the left of the start cell too, by another `--tape-size` cells
+    #[clap(long, default_value_t = false)]
+    bidirectional: bool,
+    /// What `,` stores at the end of input: 0, -1 (255 on 8-bit cells) or unchanged
+    #[clap(long, default_value_t = EofPolicy::Zero, allow_hyphen_values = true)]
+    eof: EofPolicy,
+    /// Stop the program after this many steps: instructions in the interpreters, loop
+    /// iterations in JIT code
+    #[clap(long)]
+    fuel: Option<u64>,
+    /// Cancel the program once it has run for this many seconds, it stops at the next loop
+    /// iteration
+    #[clap(long, value_name = "SECONDS", value_parser = parse_timeout)]
+    timeout: Option<Duration>,
+    /// Interpreter used without JIT: walk the IR tree, or run it lowered to flat bytecode
+    #[clap(long, value_enum, default_value_t = Interpreter::Tree)]
+    interpreter: Interpreter,
     #[clap(subcommand)]
     command: Option<Commands>,
 }
@@ -26,6 +67,10 @@ enum Commands {
         method: JitMethod,
         #[clap(long, default_value_t = false)]
         dump_ir: bool,
+        /// How the pointer is kept on the tape: explicit checks, guard-pages around a tape of
+        /// whole pages, or off for programs known to stay on the tape
+        #[clap(long, default_value_t = BoundsChecks::Explicit)]
+        bounds_checks: BoundsChecks,
     },
 }
 
@@ -35,21 +80,75 @@ enum JitMethod {
     LLVM,
 }
 
+#[derive(clap::ValueEnum, Clone, Debug)]
+enum Interpreter {
+    Tree,
+    Bytecode,
+}
+
+fn parse_timeout(s: &str) -> Result<Duration, String> {
+    let secs: f64 = s.parse().map_err(|err| format!("{}", err))?;
+    Duration::try_from_secs_f64(secs).map_err(|err| format!("{}", err))
+}
+
+/// Run `vm`, cancelling it from another thread once `timeout` has passed.
+fn run(vm: &mut impl VMInterface, timeout: Option<Duration>) -> anyhow::Result<Duration> {
+    let Some(timeout) = timeout else {
+        return vm.run();
+    };
+    let cancel = vm.cancel_handle();
+    std::thread::spawn(move || {
+        std::thread::sleep(timeout);
+        cancel.cancel();
+    });
+    vm.run().map_err(|err| match err.downcast_ref() {
+        Some(RuntimeError::Cancelled) => err.context(format!("timed out after {:?}", timeout)),
+        _ => err,
+    })
+}
+
 fn main() -> anyhow::Result<()> {
     let opt = Cli::parse();
 
-    let src = std::fs::read_to_string(&opt.source_file)?;
-    let ir = compile_peg(src.as_str())?;
+    let src = std::fs::read(&opt.source_file)?;
+    let mut ir = match ir::parse_bytes(&src, opt.strict) {
+        Ok(ir) => ir,
+        Err(ParseError::Brackets(err)) => {
+            eprint!("{}", err.render(&opt.source_file.display().to_string(), &src));
+            std::process::exit(BRACKET_MISMATCH_EXIT_CODE);
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    let config = VMConfig {
+        cell_width: opt.cell_width,
+        tape_size: opt.tape_size,
+        tape_policy: opt.tape_policy,
+        bidirectional: opt.bidirectional,
+        eof: opt.eof,
+        fuel: opt.fuel,
+        bounds_checks: match opt.command {
+            Some(Commands::Jit { bounds_checks, .. }) => bounds_checks,
+            None => BoundsChecks::default(),
+        },
+        output_chunk: None,
+    };
+
+    let mut pass_manager = PassManager::with_config(opt.opt_level, &config);
+    pass_manager.set_print_ir_after_all(opt.print_ir_after_all);
+    pass_manager.set_print_stats(opt.print_pass_stats);
+    pass_manager.run(&mut ir);
 
     let duration = match opt.command {
-        Some(Commands::Jit {dump_ir, method}) => {
+        Some(Commands::Jit {dump_ir, method, ..}) => {
             match method {
                 JitMethod::Cranelift => {
                     println!("Running program with {:?} JIT:", JitMethod::Cranelift);
-                    let mut vm = VMCranelift::new(
+                    let mut vm = VMCranelift::with_config(
                         ir,
                         Box::new(stdin().lock()),
                         Box::new(stdout().lock()),
+                        config,
                     )?;
 
                     vm.compile()?;
@@ -58,16 +157,17 @@ fn main() -> anyhow::Result<()> {
                         println!("{}", vm.get_ir());
                     }
 
-                    vm.run()?
+                    run(&mut vm, opt.timeout)?
                 }
                 JitMethod::LLVM => {
                     println!("Running program with {:?} JIT:", JitMethod::LLVM);
                     use inkwell::context::Context;
                     let context = Context::create();
-                    let mut vm = LLVM::new(
+                    let mut vm = LLVM::with_config(
                         ir,
                         Box::new(stdin().lock()),
                         Box::new(stdout().lock()),
+                        config,
                     )?;
 
                     vm.compile(&context)?;
@@ -76,20 +176,34 @@ fn main() -> anyhow::Result<()> {
                         println!("{}", vm.get_ir()?);
                     }
 
-                    vm.run()?
+                    run(&mut vm, opt.timeout)?
                 }
             }
         }
-        _ => {
-            println!("Running program without JIT:");
-            let mut vm = VM::new(
-                ir,
-                Box::new(stdin().lock()),
-                Box::new(stdout().lock()),
-            )?;
-
-            vm.run()?
-        }
+        _ => match opt.interpreter {
+            Interpreter::Tree => {
+                println!("Running program without JIT:");
+                let mut vm = VM::with_config(
+                    ir,
+                    Box::new(stdin().lock()),
+                    Box::new(stdout().lock()),
+                    config,
+                )?;
+
+                run(&mut vm, opt.timeout)?
+            }
+            Interpreter::Bytecode => {
+                println!("Running program with the bytecode interpreter:");
+                let mut vm = VMBytecode::with_config(
+                    ir,
+                    Box::new(stdin().lock()),
+                    Box::new(stdout().lock()),
+                    config,
+                )?;
+
+                run(&mut vm, opt.timeout)?
+            }
+        },
     };
 
     println!("The code took: {:?} to run", duration);
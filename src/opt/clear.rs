@@ -0,0 +1,53 @@
+use crate::ir::{for_each_block_mut, BrainfuckIR, BrainfuckNode};
+use crate::opt::Pass;
+
+/// Replace clear loops like `[-]` and `[+]` with `SetVal(0)`, and fold a following
+/// `+`/`-` run into the constant.
+pub struct ClearLoops;
+
+impl Pass for ClearLoops {
+    fn name(&self) -> &'static str {
+        "clear-loops"
+    }
+
+    fn run(&mut self, ir: &mut Vec<BrainfuckNode>) {
+        for_each_block_mut(ir, clear_block);
+    }
+}
+
+fn clear_block(block: &mut Vec<BrainfuckNode>) {
+    let mut cleared: Vec<BrainfuckNode> = Vec::with_capacity(block.len());
+    for mut node in block.drain(..) {
+        if let BrainfuckIR::Loop(body) = &node.ir {
+            if is_clear_loop(body) {
+                node.ir = BrainfuckIR::SetVal(0, 0);
+            }
+        }
+
+        // set followed by add/sub of the same cell is still a set
+        if let Some(BrainfuckNode { ir: BrainfuckIR::SetVal(val, offset), span }) = cleared.last_mut() {
+            let folded = match node.ir {
+                BrainfuckIR::AddVal(n, other) if other == *offset => Some(val.wrapping_add(n)),
+                BrainfuckIR::SubVal(n, other) if other == *offset => Some(val.wrapping_sub(n)),
+                BrainfuckIR::SetVal(n, other) if other == *offset => Some(n),
+                _ => None,
+            };
+            if let Some(folded) = folded {
+                *val = folded;
+                *span = span.merge(node.span);
+                continue;
+            }
+        }
+        cleared.push(node);
+    }
+    *block = cleared;
+}
+
+/// A loop that only adds or subtracts an odd amount always reaches zero,
+/// an even amount may never terminate so it is left alone.
+fn is_clear_loop(body: &[BrainfuckNode]) -> bool {
+    matches!(
+        body,
+        [BrainfuckNode { ir: BrainfuckIR::AddVal(n, 0) | BrainfuckIR::SubVal(n, 0), .. }] if n % 2 == 1
+    )
+}
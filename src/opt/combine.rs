@@ -0,0 +1,41 @@
+use crate::ir::{for_each_block_mut, BrainfuckIR, BrainfuckNode};
+use crate::opt::Pass;
+
+/// Merge adjacent runs of the same instruction the parser had to keep apart,
+/// e.g. `++ ++` or `> >` separated by whitespace or comments.
+pub struct CombineRuns;
+
+impl Pass for CombineRuns {
+    fn name(&self) -> &'static str {
+        "combine-runs"
+    }
+
+    fn run(&mut self, ir: &mut Vec<BrainfuckNode>) {
+        for_each_block_mut(ir, combine_block);
+    }
+}
+
+fn combine_block(block: &mut Vec<BrainfuckNode>) {
+    let mut combined: Vec<BrainfuckNode> = Vec::with_capacity(block.len());
+    for node in block.drain(..) {
+        if let Some(last) = combined.last_mut() {
+            if let Some(ir) = combine(&last.ir, &node.ir) {
+                last.ir = ir;
+                last.span = last.span.merge(node.span);
+                continue;
+            }
+        }
+        combined.push(node);
+    }
+    *block = combined;
+}
+
+fn combine(first: &BrainfuckIR, second: &BrainfuckIR) -> Option<BrainfuckIR> {
+    match (first, second) {
+        (BrainfuckIR::AddVal(a, x), BrainfuckIR::AddVal(b, y)) if x == y => Some(BrainfuckIR::AddVal(a.wrapping_add(*b), *x)),
+        (BrainfuckIR::SubVal(a, x), BrainfuckIR::SubVal(b, y)) if x == y => Some(BrainfuckIR::SubVal(a.wrapping_add(*b), *x)),
+        (BrainfuckIR::PtrMovRight(a), BrainfuckIR::PtrMovRight(b)) => Some(BrainfuckIR::PtrMovRight(a.checked_add(*b)?)),
+        (BrainfuckIR::PtrMovLeft(a), BrainfuckIR::PtrMovLeft(b)) => Some(BrainfuckIR::PtrMovLeft(a.checked_add(*b)?)),
+        _ => None,
+    }
+}
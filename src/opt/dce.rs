@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+
+use crate::ir::{for_each_block_mut, walk, BrainfuckIR, BrainfuckNode, Walk};
+use crate::opt::Pass;
+use crate::vm::{TapePolicy, VMConfig};
+
+/// Remove code that can never have an effect: loops entered with a cell known to be zero,
+/// like block comments at the start of a program or right after another loop, stores of
+/// a value the cell already holds, and adjacent runs that cancel out like `+-` or `><`.
+///
+/// Cancelling `<>` also drops the runtime error the pointer would have hit at cell 0.
+#[derive(Default)]
+pub struct DeadCode {
+    loops_removed: usize,
+    nodes_removed: usize,
+    runs_cancelled: usize,
+    /// length of a tape the pointer wraps around on
+    wrap: Option<i64>,
+}
+
+impl DeadCode {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Track cells the way they are laid out on the tape `config` describes.
+    pub fn with_config(config: &VMConfig) -> Self {
+        let wrap = (config.tape_policy == TapePolicy::Wrap).then_some(config.tape_len() as i64);
+        Self { wrap, ..Self::default() }
+    }
+}
+
+impl Pass for DeadCode {
+    fn name(&self) -> &'static str {
+        "dead-code"
+    }
+
+    fn run(&mut self, ir: &mut Vec<BrainfuckNode>) {
+        // every cell is zero when the program starts
+        let facts = Facts { rest_zero: true, wrap: self.wrap, ..Facts::default() };
+        let mut blocks = vec![(&mut *ir, facts)];
+        while let Some((block, mut facts)) = blocks.pop() {
+            self.eliminate_block(block, &mut facts);
+            // nothing from before a loop holds once its body ran an iteration
+            for node in block.iter_mut() {
+                if let BrainfuckIR::Loop(body) = &mut node.ir {
+                    blocks.push((body, Facts { wrap: self.wrap, ..Facts::default() }));
+                }
+            }
+        }
+        for_each_block_mut(ir, |block| self.cancel_block(block));
+    }
+
+    fn statistics(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("loops removed", self.loops_removed),
+            ("instructions removed", self.nodes_removed),
+            ("runs cancelled", self.runs_cancelled),
+        ]
+    }
+}
+
+/// What is known about the tape at one point of the program.
+#[derive(Default)]
+struct Facts {
+    /// how far the pointer moved since the facts started being collected
+    origin: i64,
+    /// cell contents by position modulo 2^32, `None` marks a cell whose value is unknown.
+    /// For narrower cells this is conservative, not exact: values equal modulo 2^32 are equal
+    /// at every width, but values that differ can still be equal modulo 2^8
+    cells: HashMap<i64, Option<u32>>,
+    /// cells missing from `cells` are zero, only holds until the pointer moves by an unknown amount
+    rest_zero: bool,
+    /// positions are taken modulo this tape length
+    wrap: Option<i64>,
+}
+
+impl Facts {
+    /// Only the current cell is known to be zero, e.g. right after a loop.
+    fn after_loop(wrap: Option<i64>) -> Self {
+        Self { cells: HashMap::from([(0, Some(0))]), wrap, ..Self::default() }
+    }
+
+    fn position(&self, offset: i32) -> i64 {
+        let position = self.origin + offset as i64;
+        self.wrap.map_or(position, |len| position.rem_euclid(len))
+    }
+
+    fn get(&self, offset: i32) -> Option<u32> {
+        match self.cells.get(&self.position(offset)) {
+            Some(value) => *value,
+            None if self.rest_zero => Some(0),
+            None => None,
+        }
+    }
+
+    fn set(&mut self, offset: i32, value: Option<u32>) {
+        self.cells.insert(self.position(offset), value);
+    }
+}
+
+impl DeadCode {
+    /// Drop the dead nodes of `block`, the bodies of the loops left are up to the caller.
+    fn eliminate_block(&mut self, block: &mut Vec<BrainfuckNode>, facts: &mut Facts) {
+        let mut live = Vec::with_capacity(block.len());
+        for mut node in block.drain(..) {
+            let dead = match &mut node.ir {
+                BrainfuckIR::Loop(_) => {
+                    if facts.get(0) == Some(0) {
+                        self.loops_removed += 1;
+                        true
+                    } else {
+                        *facts = Facts::after_loop(self.wrap);
+                        false
+                    }
+                }
+                BrainfuckIR::AddVal(n, offset) => {
+                    let value = facts.get(*offset).map(|value| value.wrapping_add(*n));
+                    facts.set(*offset, value);
+                    false
+                }
+                BrainfuckIR::SubVal(n, offset) => {
+                    let value = facts.get(*offset).map(|value| value.wrapping_sub(*n));
+                    facts.set(*offset, value);
+                    false
+                }
+                BrainfuckIR::SetVal(n, offset) => {
+                    let dead = facts.get(*offset) == Some(*n);
+                    facts.set(*offset, Some(*n));
+                    dead
+                }
+                BrainfuckIR::MulAdd { src, offset, factor } => match facts.get(*src) {
+                    Some(0) => true,
+                    src => {
+                        let value = src
+                            .zip(facts.get(*offset))
+                            .map(|(src, value)| value.wrapping_add(src.wrapping_mul(*factor)));
+                        facts.set(*offset, value);
+                        false
+                    }
+                },
+                BrainfuckIR::GetByte(offset) => {
+                    facts.set(*offset, None);
+                    false
+                }
+                BrainfuckIR::PutByte(_) | BrainfuckIR::PutBytes(_) => false,
+                BrainfuckIR::PtrMovRight(n) => {
+                    facts.origin += *n as i64;
+                    false
+                }
+                BrainfuckIR::PtrMovLeft(n) => {
+                    facts.origin -= *n as i64;
+                    false
+                }
+                BrainfuckIR::ScanRight(_) | BrainfuckIR::ScanLeft(_) => {
+                    *facts = Facts::after_loop(self.wrap);
+                    false
+                }
+            };
+            if dead {
+                self.nodes_removed += count_nodes(&node);
+            } else {
+                live.push(node);
+            }
+        }
+        *block = live;
+    }
+
+    fn cancel_block(&mut self, block: &mut Vec<BrainfuckNode>) {
+        let mut kept: Vec<BrainfuckNode> = Vec::with_capacity(block.len());
+        for node in block.drain(..) {
+            // comparing against the last kept node also catches nested pairs like `+><-`
+            if let Some(last) = kept.last_mut() {
+                if let Some(net) = cancel(&last.ir, &node.ir) {
+                    self.runs_cancelled += 1;
+                    match net {
+                        Some(ir) => {
+                            last.ir = ir;
+                            last.span = last.span.merge(node.span);
+                            self.nodes_removed += 1;
+                        }
+                        None => {
+                            kept.pop();
+                            self.nodes_removed += 2;
+                        }
+                    }
+                    continue;
+                }
+            }
+            kept.push(node);
+        }
+        *block = kept;
+    }
+}
+
+/// The net effect of two opposite runs, `Some(None)` when they cancel out completely.
+fn cancel(first: &BrainfuckIR, second: &BrainfuckIR) -> Option<Option<BrainfuckIR>> {
+    let net = match (first, second) {
+        (BrainfuckIR::AddVal(a, x), BrainfuckIR::SubVal(b, y)) if x == y => {
+            Some(BrainfuckIR::AddVal(a.wrapping_sub(*b), *x)).filter(|_| a != b)
+        }
+        (BrainfuckIR::SubVal(a, x), BrainfuckIR::AddVal(b, y)) if x == y => {
+            Some(BrainfuckIR::SubVal(a.wrapping_sub(*b), *x)).filter(|_| a != b)
+        }
+        (BrainfuckIR::PtrMovRight(a), BrainfuckIR::PtrMovLeft(b)) => match a.cmp(b) {
+            std::cmp::Ordering::Greater => Some(BrainfuckIR::PtrMovRight(a - b)),
+            std::cmp::Ordering::Less => Some(BrainfuckIR::PtrMovLeft(b - a)),
+            std::cmp::Ordering::Equal => None,
+        },
+        (BrainfuckIR::PtrMovLeft(a), BrainfuckIR::PtrMovRight(b)) => match a.cmp(b) {
+            std::cmp::Ordering::Greater => Some(BrainfuckIR::PtrMovLeft(a - b)),
+            std::cmp::Ordering::Less => Some(BrainfuckIR::PtrMovRight(b - a)),
+            std::cmp::Ordering::Equal => None,
+        },
+        _ => return None,
+    };
+    Some(net)
+}
+
+/// A node together with everything nested in it.
+fn count_nodes(node: &BrainfuckNode) -> usize {
+    walk(std::slice::from_ref(node)).filter(|step| matches!(step, Walk::Enter(_))).count()
+}
@@ -0,0 +1,89 @@
+use crate::ir::{for_each_block_mut, BrainfuckIR, BrainfuckNode, Span};
+use crate::opt::Pass;
+
+/// Sink pointer movement to the end of each basic block, instructions in between
+/// address their cell by offset instead, so `>+>+<<` becomes `add [+1] 1; add [+2] 1`.
+///
+/// Loops and scans move the pointer by an unknown amount, so the pending movement is
+/// emitted right before them and at the end of every loop body.
+pub struct DeferMoves;
+
+impl Pass for DeferMoves {
+    fn name(&self) -> &'static str {
+        "defer-moves"
+    }
+
+    fn run(&mut self, ir: &mut Vec<BrainfuckNode>) {
+        for_each_block_mut(ir, defer_block);
+    }
+}
+
+fn defer_block(block: &mut Vec<BrainfuckNode>) {
+    let mut deferred = Vec::with_capacity(block.len());
+    // pointer movement not emitted yet, and the span of the moves it came from
+    let mut pending: Option<(i32, Span)> = None;
+
+    for mut node in block.drain(..) {
+        let moved = match node.ir {
+            BrainfuckIR::PtrMovRight(n) => i32::try_from(n).ok(),
+            BrainfuckIR::PtrMovLeft(n) => i32::try_from(n).ok().map(|n| -n),
+            _ => None,
+        };
+        if let Some(moved) = moved {
+            let (offset, span) = pending.unwrap_or((0, node.span));
+            if let Some(offset) = offset.checked_add(moved) {
+                pending = Some((offset, span.merge(node.span)));
+                continue;
+            }
+        }
+
+        let offset = pending.map_or(0, |(offset, _)| offset);
+        let fits = match &mut node.ir {
+            BrainfuckIR::AddVal(_, cell)
+            | BrainfuckIR::SubVal(_, cell)
+            | BrainfuckIR::SetVal(_, cell)
+            | BrainfuckIR::PutByte(cell)
+            | BrainfuckIR::GetByte(cell) => shift(cell, offset),
+            BrainfuckIR::MulAdd { src, offset: cell, .. } => {
+                let mut shifted = (*src, *cell);
+                let fits = shift(&mut shifted.0, offset) && shift(&mut shifted.1, offset);
+                if fits {
+                    (*src, *cell) = shifted;
+                }
+                fits
+            }
+            _ => false,
+        };
+        if !fits {
+            // the pointer has to be where this instruction expects it
+            flush(&mut deferred, &mut pending);
+        }
+        deferred.push(node);
+    }
+    flush(&mut deferred, &mut pending);
+
+    *block = deferred;
+}
+
+/// Add `offset` to a cell offset, `false` if the result doesn't fit.
+fn shift(cell: &mut i32, offset: i32) -> bool {
+    match cell.checked_add(offset) {
+        Some(shifted) => {
+            *cell = shifted;
+            true
+        }
+        None => false,
+    }
+}
+
+fn flush(block: &mut Vec<BrainfuckNode>, pending: &mut Option<(i32, Span)>) {
+    match pending.take() {
+        Some((offset, span)) if offset > 0 => {
+            block.push(BrainfuckNode::new(BrainfuckIR::PtrMovRight(offset as u32), span));
+        }
+        Some((offset, span)) if offset < 0 => {
+            block.push(BrainfuckNode::new(BrainfuckIR::PtrMovLeft(offset.unsigned_abs()), span));
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,238 @@
+use crate::ir::{BrainfuckIR, BrainfuckNode};
+use crate::opt::Pass;
+use crate::vm::{TapePolicy, VMConfig};
+
+/// Steps a program may run at compile time before evaluation gives up.
+pub const DEFAULT_BUDGET: usize = 1 << 20;
+
+/// Run the program at compile time until it needs input, then replace everything that
+/// ran with one write of the output it produced and the tape state it left behind.
+///
+/// Only whole top-level instructions are evaluated: a loop that reads input, runs out of
+/// budget or leaves the tape is kept as is, together with everything after it.
+pub struct PartialEval {
+    budget: usize,
+    config: VMConfig,
+    nodes_evaluated: usize,
+    bytes_precomputed: usize,
+    steps: usize,
+}
+
+impl PartialEval {
+    /// Evaluate at most `budget` instructions, loop iterations included, on the machine
+    /// the residual program will run on.
+    pub fn new(budget: usize, config: &VMConfig) -> Self {
+        Self {
+            budget,
+            config: config.clone(),
+            nodes_evaluated: 0,
+            bytes_precomputed: 0,
+            steps: 0,
+        }
+    }
+}
+
+impl Default for PartialEval {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUDGET, &VMConfig::default())
+    }
+}
+
+impl Pass for PartialEval {
+    fn name(&self) -> &'static str {
+        "partial-eval"
+    }
+
+    fn run(&mut self, ir: &mut Vec<BrainfuckNode>) {
+        let mut machine = Machine {
+            tape: Vec::new(),
+            low: 0,
+            ptr: 0,
+            output: Vec::new(),
+            fuel: self.budget,
+            config: self.config.clone(),
+        };
+        let mut evaluated = 0;
+        for node in ir.iter() {
+            // loops and scans may stop halfway, so they run on a copy that is only kept once finished
+            let repeats = matches!(node.ir, BrainfuckIR::Loop(_) | BrainfuckIR::ScanRight(_) | BrainfuckIR::ScanLeft(_));
+            let done = if repeats {
+                let mut trial = machine.clone();
+                trial.step(node).map(|()| machine = trial)
+            } else {
+                machine.step(node)
+            };
+            if done.is_none() {
+                break;
+            }
+            evaluated += 1;
+        }
+        self.steps += self.budget - machine.fuel;
+        if evaluated == 0 {
+            return;
+        }
+
+        let rest = ir.split_off(evaluated);
+        let span = ir.iter().map(|node| node.span).reduce(|a, b| a.merge(b)).unwrap_or_default();
+        self.nodes_evaluated += evaluated;
+        self.bytes_precomputed += machine.output.len();
+
+        ir.clear();
+        if !machine.output.is_empty() {
+            ir.push(BrainfuckNode::new(BrainfuckIR::PutBytes(machine.output), span));
+        }
+        // the tape only matters if something is left to run
+        if !rest.is_empty() {
+            for (cell, &value) in machine.tape.iter().enumerate().filter(|(_, &value)| value != 0) {
+                let offset = machine.low + cell as i64;
+                ir.push(BrainfuckNode::new(BrainfuckIR::SetVal(value, offset as i32), span));
+            }
+            match machine.ptr.cmp(&0) {
+                std::cmp::Ordering::Greater => {
+                    ir.push(BrainfuckNode::new(BrainfuckIR::PtrMovRight(machine.ptr as u32), span));
+                }
+                std::cmp::Ordering::Less => {
+                    ir.push(BrainfuckNode::new(BrainfuckIR::PtrMovLeft(machine.ptr.unsigned_abs() as u32), span));
+                }
+                std::cmp::Ordering::Equal => {}
+            }
+        }
+        ir.extend(rest);
+    }
+
+    fn statistics(&self) -> Vec<(&'static str, usize)> {
+        vec![
+            ("instructions evaluated", self.nodes_evaluated),
+            ("output bytes precomputed", self.bytes_precomputed),
+            ("steps run", self.steps),
+        ]
+    }
+}
+
+/// The compile time interpreter, positions count from the cell the program starts on.
+#[derive(Clone)]
+struct Machine {
+    /// cells from position `low` on, every cell outside of it is zero
+    tape: Vec<u32>,
+    low: i64,
+    ptr: i64,
+    output: Vec<u8>,
+    fuel: usize,
+    config: VMConfig,
+}
+
+impl Machine {
+    /// Execute one node, `None` if it can't be evaluated at compile time.
+    fn step(&mut self, node: &BrainfuckNode) -> Option<()> {
+        // the bodies of the loops being run, innermost last, with the index of their next node
+        let mut loops: Vec<(&[BrainfuckNode], usize)> = Vec::new();
+        let mut node = node;
+        loop {
+            self.execute(node, &mut loops)?;
+            node = loop {
+                let Some((body, next)) = loops.last_mut() else {
+                    return Some(());
+                };
+                if let Some(node) = body.get(*next) {
+                    *next += 1;
+                    break node;
+                }
+                // the end of an iteration
+                self.fuel = self.fuel.checked_sub(1)?;
+                if *self.cell(0)? != 0 {
+                    *next = 0;
+                } else {
+                    loops.pop();
+                }
+            };
+        }
+    }
+
+    /// Execute a single instruction, a loop that is entered pushes its body onto `loops`.
+    fn execute<'a>(&mut self, node: &'a BrainfuckNode, loops: &mut Vec<(&'a [BrainfuckNode], usize)>) -> Option<()> {
+        self.fuel = self.fuel.checked_sub(1)?;
+        match &node.ir {
+            BrainfuckIR::AddVal(n, offset) => {
+                let val = self.cell(*offset)?.wrapping_add(*n);
+                self.store(*offset, val)?;
+            }
+            BrainfuckIR::SubVal(n, offset) => {
+                let val = self.cell(*offset)?.wrapping_sub(*n);
+                self.store(*offset, val)?;
+            }
+            BrainfuckIR::SetVal(n, offset) => self.store(*offset, *n)?,
+            BrainfuckIR::MulAdd { src, offset, factor } => {
+                let val = *self.cell(*src)?;
+                if val != 0 {
+                    let target = self.cell(*offset)?.wrapping_add(val.wrapping_mul(*factor));
+                    self.store(*offset, target)?;
+                }
+            }
+            BrainfuckIR::PtrMovRight(n) => self.ptr = self.index(*n as i64)?,
+            BrainfuckIR::PtrMovLeft(n) => self.ptr = self.index(-(*n as i64))?,
+            BrainfuckIR::ScanRight(n) => self.scan(*n as i64)?,
+            BrainfuckIR::ScanLeft(n) => self.scan(-(*n as i64))?,
+            BrainfuckIR::PutByte(offset) => {
+                let val = *self.cell(*offset)?;
+                self.output.push(val as u8);
+            }
+            BrainfuckIR::PutBytes(bytes) => self.output.extend_from_slice(bytes),
+            // input is only known at run time
+            BrainfuckIR::GetByte(_) => return None,
+            BrainfuckIR::Loop(body) => {
+                if *self.cell(0)? != 0 {
+                    loops.push((body, 0));
+                }
+            }
+        }
+        Some(())
+    }
+
+    fn scan(&mut self, stride: i64) -> Option<()> {
+        while *self.cell(0)? != 0 {
+            self.ptr = self.index(stride)?;
+            self.fuel = self.fuel.checked_sub(1)?;
+        }
+        Some(())
+    }
+
+    /// Position `offset` away from the pointer as the tape policy says, `None` if that
+    /// is off the tape.
+    fn index(&self, offset: i64) -> Option<i64> {
+        let position = self.ptr + offset;
+        let origin = self.config.origin() as i64;
+        let len = self.config.tape_len() as i64;
+        let index = position + origin;
+        match self.config.tape_policy {
+            _ if (0..len).contains(&index) => Some(position),
+            TapePolicy::Wrap => Some(index.rem_euclid(len) - origin),
+            TapePolicy::Grow if index >= len || self.config.bidirectional => Some(position),
+            _ => None,
+        }
+    }
+
+    fn cell(&mut self, offset: i32) -> Option<&mut u32> {
+        let position = self.index(offset as i64)?;
+        if self.tape.is_empty() {
+            self.low = position;
+        }
+        if position < self.low {
+            // at least double the stored cells so a walk to the left stays cheap
+            let grow = ((self.low - position) as usize).max(self.tape.len());
+            self.tape.splice(0..0, std::iter::repeat_n(0, grow));
+            self.low -= grow as i64;
+        }
+        let cell = (position - self.low) as usize;
+        if cell >= self.tape.len() {
+            self.tape.resize(cell + 1, 0);
+        }
+        Some(&mut self.tape[cell])
+    }
+
+    /// Store `val` wrapped around at the cell width.
+    fn store(&mut self, offset: i32, val: u32) -> Option<()> {
+        let cell_width = self.config.cell_width;
+        *self.cell(offset)? = cell_width.truncate(val);
+        Some(())
+    }
+}
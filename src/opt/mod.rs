@@ -0,0 +1,136 @@
+mod clear;
+mod combine;
+mod dce;
+mod defer;
+mod eval;
+mod mul;
+mod scan;
+
+use crate::ir::{print_ir, BrainfuckNode};
+use crate::vm::VMConfig;
+
+pub use clear::ClearLoops;
+pub use combine::CombineRuns;
+pub use dce::DeadCode;
+pub use defer::DeferMoves;
+pub use eval::{PartialEval, DEFAULT_BUDGET};
+pub use mul::MulLoops;
+pub use scan::ScanLoops;
+
+/// A transformation over the whole IR tree.
+pub trait Pass {
+    fn name(&self) -> &'static str;
+    fn run(&mut self, ir: &mut Vec<BrainfuckNode>);
+
+    /// Counters describing what the pass changed, summed over all of its runs.
+    fn statistics(&self) -> Vec<(&'static str, usize)> {
+        Vec::new()
+    }
+}
+
+/// Runs a sequence of passes, optionally printing the IR after each one.
+#[derive(Default)]
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+    print_ir_after_all: bool,
+    print_stats: bool,
+}
+
+impl PassManager {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The pipeline for an optimization level, `0` runs no passes at all.
+    pub fn with_level(level: u8) -> Self {
+        Self::with_config(level, &VMConfig::default())
+    }
+
+    /// The pipeline for an optimization level, for programs run on a machine set up with `config`.
+    pub fn with_config(level: u8, config: &VMConfig) -> Self {
+        let mut manager = Self::new();
+        if level >= 1 {
+            manager.add_pass(CombineRuns);
+            manager.add_pass(ClearLoops);
+            manager.add_pass(DeadCode::with_config(config));
+        }
+        if level >= 2 {
+            manager.add_pass(MulLoops);
+            // fold the clears left behind by multiply loops
+            manager.add_pass(ClearLoops);
+            manager.add_pass(ScanLoops);
+            // steps run at compile time would escape the fuel limit
+            if config.fuel.is_none() {
+                manager.add_pass(PartialEval::new(DEFAULT_BUDGET, config));
+            }
+        }
+        if level >= 3 {
+            manager.add_pass(DeferMoves);
+        }
+        manager
+    }
+
+    pub fn add_pass<P: Pass + 'static>(&mut self, pass: P) {
+        self.passes.push(Box::new(pass));
+    }
+
+    pub fn set_print_ir_after_all(&mut self, enable: bool) {
+        self.print_ir_after_all = enable;
+    }
+
+    pub fn set_print_stats(&mut self, enable: bool) {
+        self.print_stats = enable;
+    }
+
+    /// The statistics of every pass that reported any, in pipeline order.
+    pub fn statistics(&self) -> Vec<(&'static str, Vec<(&'static str, usize)>)> {
+        self.passes
+            .iter()
+            .map(|pass| (pass.name(), pass.statistics()))
+            .filter(|(_, stats)| !stats.is_empty())
+            .collect()
+    }
+
+    pub fn run(&mut self, ir: &mut Vec<BrainfuckNode>) {
+        for pass in &mut self.passes {
+            pass.run(ir);
+            if self.print_ir_after_all {
+                eprintln!("; *** IR after {} ***", pass.name());
+                eprint!("{}", print_ir(ir));
+            }
+        }
+        if self.print_stats {
+            for (name, stats) in self.statistics() {
+                eprintln!("; *** statistics for {} ***", name);
+                for (what, count) in stats {
+                    eprintln!("; {:>8} {}", count, what);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(manager: &PassManager) -> Vec<&'static str> {
+        manager.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    #[test]
+    fn pipelines() {
+        let o1 = ["combine-runs", "clear-loops", "dead-code"];
+        let o2 = [&o1[..], &["mul-loops", "clear-loops", "scan-loops", "partial-eval"]].concat();
+        let o3 = [&o2[..], &["defer-moves"]].concat();
+        assert!(names(&PassManager::with_level(0)).is_empty());
+        assert_eq!(names(&PassManager::with_level(1)), o1);
+        assert_eq!(names(&PassManager::with_level(2)), o2);
+        assert_eq!(names(&PassManager::with_level(3)), o3);
+
+        // a fuel limit leaves out compile-time evaluation
+        let config = VMConfig { fuel: Some(100), ..VMConfig::default() };
+        let fueled: Vec<_> = o3.iter().copied().filter(|&name| name != "partial-eval").collect();
+        assert_eq!(names(&PassManager::with_config(3, &config)), fueled);
+    }
+}
@@ -0,0 +1,67 @@
+use std::collections::BTreeMap;
+
+use crate::ir::{for_each_block_mut, BrainfuckIR, BrainfuckNode};
+use crate::opt::Pass;
+
+/// Replace multiply/copy loops like `[->+>++<<]` with a `MulAdd` per touched cell
+/// followed by clearing the loop counter.
+///
+/// Only loops made of `+-<>` are considered, the pointer must end where it started and
+/// the counter cell must be decremented by exactly one per iteration. Runs after moves
+/// were deferred are fine too, their offsets are added to the pointer position.
+pub struct MulLoops;
+
+impl Pass for MulLoops {
+    fn name(&self) -> &'static str {
+        "mul-loops"
+    }
+
+    fn run(&mut self, ir: &mut Vec<BrainfuckNode>) {
+        for_each_block_mut(ir, mul_block);
+    }
+}
+
+fn mul_block(block: &mut Vec<BrainfuckNode>) {
+    let mut lowered = Vec::with_capacity(block.len());
+    for node in block.drain(..) {
+        if let BrainfuckIR::Loop(body) = &node.ir {
+            if let Some(deltas) = loop_deltas(body) {
+                lowered.extend(deltas.into_iter().map(|(offset, factor)| {
+                    BrainfuckNode::new(BrainfuckIR::MulAdd { src: 0, offset, factor }, node.span)
+                }));
+                lowered.push(BrainfuckNode::new(BrainfuckIR::SetVal(0, 0), node.span));
+                continue;
+            }
+        }
+        lowered.push(node);
+    }
+    *block = lowered;
+}
+
+/// The amount added to each cell relative to the counter per iteration,
+/// or `None` if the loop isn't a multiply loop.
+fn loop_deltas(body: &[BrainfuckNode]) -> Option<BTreeMap<i32, u32>> {
+    let mut offset = 0i32;
+    let mut deltas = BTreeMap::new();
+    for node in body {
+        match node.ir {
+            BrainfuckIR::AddVal(n, cell) => {
+                let delta = deltas.entry(offset.checked_add(cell)?).or_insert(0u32);
+                *delta = delta.wrapping_add(n);
+            }
+            BrainfuckIR::SubVal(n, cell) => {
+                let delta = deltas.entry(offset.checked_add(cell)?).or_insert(0u32);
+                *delta = delta.wrapping_sub(n);
+            }
+            BrainfuckIR::PtrMovRight(n) => offset = offset.checked_add(i32::try_from(n).ok()?)?,
+            BrainfuckIR::PtrMovLeft(n) => offset = offset.checked_sub(i32::try_from(n).ok()?)?,
+            _ => return None,
+        }
+    }
+
+    if offset != 0 || deltas.remove(&0) != Some(u32::MAX) {
+        return None;
+    }
+    deltas.retain(|_, factor| *factor != 0);
+    Some(deltas)
+}
@@ -0,0 +1,27 @@
+use crate::ir::{for_each_block_mut, BrainfuckIR, BrainfuckNode};
+use crate::opt::Pass;
+
+/// Replace loops that only move the pointer, like `[>]` or `[<<<<]`, with a scan for a zero cell.
+pub struct ScanLoops;
+
+impl Pass for ScanLoops {
+    fn name(&self) -> &'static str {
+        "scan-loops"
+    }
+
+    fn run(&mut self, ir: &mut Vec<BrainfuckNode>) {
+        for_each_block_mut(ir, |block| scan_block(block));
+    }
+}
+
+fn scan_block(block: &mut [BrainfuckNode]) {
+    for node in block {
+        if let BrainfuckIR::Loop(body) = &node.ir {
+            match body.as_slice() {
+                [BrainfuckNode { ir: BrainfuckIR::PtrMovRight(n), .. }] => node.ir = BrainfuckIR::ScanRight(*n),
+                [BrainfuckNode { ir: BrainfuckIR::PtrMovLeft(n), .. }] => node.ir = BrainfuckIR::ScanLeft(*n),
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,262 @@
+use std::{io::{Read, Write}, time::Duration};
+
+use crate::ir::{walk, BrainfuckIR, BrainfuckNode, Position, Walk};
+use crate::vm::vm::VMContext;
+use crate::vm::{CancelHandle, Cell, CellWidth, TapePolicy, VMConfig, VMInterface, Stop, IO};
+
+/// One instruction of the flat program, loops are lowered to jumps.
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    /// Add to the cell at an offset, subtracting adds the negated value
+    Add(u32, i32),
+    Set(u32, i32),
+    MulAdd { src: i32, offset: i32, factor: u32 },
+    Move(isize),
+    Scan(isize),
+    Put(i32),
+    /// Write `data[start..end]`
+    Write(u32, u32),
+    Get(i32),
+    /// Continue at the target, past the matching `LoopEnd`, if the current cell is zero
+    LoopStart(u32),
+    /// Continue at the target, the start of the loop body, unless the current cell is zero
+    LoopEnd(u32),
+}
+
+/// The IR lowered to a flat instruction array with every jump target resolved.
+struct Program {
+    code: Vec<Op>,
+    /// Where each instruction came from, for errors
+    positions: Vec<Position>,
+    /// The bytes of every `PutBytes`
+    data: Vec<u8>,
+}
+
+impl Program {
+    fn new(ir: &[BrainfuckNode]) -> Self {
+        let mut program = Self { code: Vec::new(), positions: Vec::new(), data: Vec::new() };
+        program.lower(ir);
+        program
+    }
+
+    fn lower(&mut self, ir: &[BrainfuckNode]) {
+        // the index of the `LoopStart` of every loop being lowered, innermost last
+        let mut starts = Vec::new();
+        for step in walk(ir) {
+            let node = match step {
+                Walk::Enter(node) => node,
+                Walk::Exit(node) => {
+                    let start = starts.pop().expect("every loop is entered before it ends");
+                    self.push(Op::LoopEnd(start as u32 + 1), node.span.start);
+                    self.code[start] = Op::LoopStart(self.code.len() as u32);
+                    continue;
+                }
+            };
+            let op = match &node.ir {
+                BrainfuckIR::AddVal(n, offset) => Op::Add(*n, *offset),
+                BrainfuckIR::SubVal(n, offset) => Op::Add(n.wrapping_neg(), *offset),
+                BrainfuckIR::SetVal(n, offset) => Op::Set(*n, *offset),
+                BrainfuckIR::MulAdd { src, offset, factor } => Op::MulAdd { src: *src, offset: *offset, factor: *factor },
+                BrainfuckIR::PtrMovRight(n) => Op::Move(*n as isize),
+                BrainfuckIR::PtrMovLeft(n) => Op::Move(-(*n as isize)),
+                BrainfuckIR::ScanRight(n) => Op::Scan(*n as isize),
+                BrainfuckIR::ScanLeft(n) => Op::Scan(-(*n as isize)),
+                BrainfuckIR::PutByte(offset) => Op::Put(*offset),
+                BrainfuckIR::PutBytes(bytes) => {
+                    let start = self.data.len() as u32;
+                    self.data.extend_from_slice(bytes);
+                    Op::Write(start, self.data.len() as u32)
+                }
+                BrainfuckIR::GetByte(offset) => Op::Get(*offset),
+                BrainfuckIR::Loop(_) => {
+                    starts.push(self.push(Op::LoopStart(0), node.span.start));
+                    continue;
+                }
+            };
+            self.push(op, node.span.start);
+        }
+    }
+
+    /// Append `op` and return its index.
+    fn push(&mut self, op: Op, position: Position) -> usize {
+        self.code.push(op);
+        self.positions.push(position);
+        self.code.len() - 1
+    }
+}
+
+/// The dispatch loop for one cell type, picked once when the VM is created.
+trait Dispatch {
+    /// Run `program` from `pc` on, leaving `pc` at the instruction that failed.
+    fn dispatch(&mut self, program: &Program, ptr: &mut usize, pc: &mut usize) -> anyhow::Result<()>;
+    /// Why the last dispatch stopped early, at a `,` that would block or after a `.` that
+    /// filled the output, if it did.
+    fn take_stopped(&mut self) -> Option<Stop>;
+    fn io(&mut self) -> &mut IO;
+}
+
+impl<C: Cell> Dispatch for VMContext<C> {
+    fn dispatch(&mut self, program: &Program, ptr: &mut usize, pc: &mut usize) -> anyhow::Result<()> {
+        let code = &program.code[..];
+        while let Some(op) = code.get(*pc) {
+            self.step()?;
+            match *op {
+                Op::Add(n, offset) => {
+                    let cell = self.at(ptr, offset as isize)?;
+                    self.memory[cell] = C::from_u32(self.memory[cell].to_u32().wrapping_add(n));
+                }
+                Op::Set(n, offset) => {
+                    let cell = self.at(ptr, offset as isize)?;
+                    self.memory[cell] = C::from_u32(n);
+                }
+                Op::MulAdd { src, offset, factor } => {
+                    let src = self.at(ptr, src as isize)?;
+                    let val = self.memory[src].to_u32();
+                    if val != 0 {
+                        let target = self.at(ptr, offset as isize)?;
+                        let product = val.wrapping_mul(factor);
+                        self.memory[target] = C::from_u32(self.memory[target].to_u32().wrapping_add(product));
+                    }
+                }
+                Op::Move(delta) => {
+                    *ptr = self.at(ptr, delta)?;
+                }
+                Op::Scan(stride) => {
+                    *ptr = self.scan(ptr, stride)?;
+                }
+                Op::Put(offset) => {
+                    // only the low byte of a wide cell is written
+                    let cell = self.at(ptr, offset as isize)?;
+                    self.write(&[self.memory[cell].to_u32() as u8])?;
+                }
+                Op::Write(start, end) => {
+                    self.write(&program.data[start as usize..end as usize])?;
+                }
+                Op::Get(offset) => {
+                    let cell = self.at(ptr, offset as isize)?;
+                    let byte = self.read()?;
+                    let val = self.eof.store(byte, self.memory[cell].to_u32(), self.cell_width);
+                    self.memory[cell] = C::from_u32(val);
+                }
+                Op::LoopStart(target) => {
+                    if self.memory[*ptr] == C::default() {
+                        *pc = target as usize;
+                        continue;
+                    }
+                }
+                Op::LoopEnd(target) => {
+                    // the end of every iteration, like in `VM`
+                    self.poll_cancel()?;
+                    if self.memory[*ptr] != C::default() {
+                        *pc = target as usize;
+                        continue;
+                    }
+                }
+            }
+            *pc += 1;
+        }
+        Ok(())
+    }
+
+    fn take_stopped(&mut self) -> Option<Stop> {
+        self.stopped.take()
+    }
+
+    fn io(&mut self) -> &mut IO {
+        &mut self.io
+    }
+}
+
+impl<C: Cell> VMContext<C> {
+    /// `VMContext::index` with the common case of a cell on the tape inlined.
+    #[inline(always)]
+    fn at(&mut self, ptr: &mut usize, delta: isize) -> anyhow::Result<usize> {
+        // left of the tape wraps around to a huge index
+        let index = ptr.wrapping_add_signed(delta);
+        if index < self.memory.len() {
+            Ok(index)
+        } else {
+            self.index(ptr, delta)
+        }
+    }
+}
+
+/// An interpreter that runs the IR lowered to flat bytecode, instead of walking the tree
+/// like `VM`.
+pub struct VMBytecode {
+    program: Program,
+    context: Box<dyn Dispatch>,
+    origin: usize,
+    cancel: CancelHandle,
+    /// the pointer and the instruction a suspended run continues at
+    suspended: Option<(usize, usize)>,
+    output_chunk: Option<usize>,
+}
+
+impl VMInterface for VMBytecode {
+    fn with_config(
+        ir: Vec<BrainfuckNode>,
+        input: Box<dyn Read>,
+        output: Box<dyn Write>,
+        config: VMConfig,
+    ) -> anyhow::Result<Self> {
+        config.check_tape("the bytecode interpreter", &[TapePolicy::Fixed, TapePolicy::Wrap, TapePolicy::Grow], usize::MAX)?;
+        let cancel = CancelHandle::default();
+        let context: Box<dyn Dispatch> = match config.cell_width {
+            CellWidth::U8 => Box::new(VMContext::<u8>::new(&config, input, output, cancel.clone())),
+            CellWidth::U16 => Box::new(VMContext::<u16>::new(&config, input, output, cancel.clone())),
+            CellWidth::U32 => Box::new(VMContext::<u32>::new(&config, input, output, cancel.clone())),
+        };
+
+        Ok(Self {
+            program: Program::new(&ir),
+            context,
+            origin: config.origin(),
+            cancel,
+            suspended: None,
+            output_chunk: config.output_chunk,
+        })
+    }
+
+    fn run(&mut self) -> anyhow::Result<Duration> {
+        let clock = quanta::Clock::new();
+
+        // a suspended run is abandoned, and a `,` that would block is an error
+        self.suspended = None;
+        self.context.io().limit_output(None);
+        let start = clock.now();
+        let mut ptr = self.origin;
+        let mut pc = 0;
+        // report where in the source a failing instruction came from
+        let result = self.context.dispatch(&self.program, &mut ptr, &mut pc)
+            .map_err(|err| err.context(format!("at {}", self.program.positions[pc])));
+        let end = clock.now();
+        self.context.take_stopped();
+        self.context.io().finish(result)?;
+
+        Ok(end - start)
+    }
+
+    fn resume(&mut self) -> anyhow::Result<Stop> {
+        self.context.io().limit_output(self.output_chunk);
+        let (mut ptr, mut pc) = self.suspended.take().unwrap_or((self.origin, 0));
+        let result = self.context.dispatch(&self.program, &mut ptr, &mut pc)
+            .map_err(|err| err.context(format!("at {}", self.program.positions[pc])));
+        if let Some(stopped) = self.context.take_stopped() {
+            // `pc` is still at the `,` or `.`, and the output was flushed before the read or
+            // after the write. The `,` runs again, the `.` is done.
+            if stopped == Stop::OutputFull {
+                pc += 1;
+            }
+            self.suspended = Some((ptr, pc));
+            return Ok(stopped);
+        }
+        self.context.io().finish(result)?;
+
+        Ok(Stop::Done)
+    }
+
+    fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
+}
@@ -1,14 +1,21 @@
-use std::{io::{Read, Write}, time::Duration};
-use cranelift::codegen::ir::FuncRef;
+use std::{cell::Cell, io::{Read, Write}, time::Duration};
+use cranelift::codegen::ir::{FuncRef, SourceLoc, StackSlot};
 use cranelift::codegen::write_function;
+use cranelift::frontend::Switch;
 use cranelift::prelude::*;
 use cranelift_jit::{JITBuilder, JITModule};
-use cranelift_module::{FuncId, Linkage, Module};
+use cranelift_module::{DataDescription, FuncId, Linkage, Module};
+use thiserror::Error;
 
-use crate::ir::BrainfuckIR;
-use crate::vm::{VMInterface, IO, MEMORY_SIZE, bf_put, bf_get};
+use crate::ir::{walk, BrainfuckIR, BrainfuckNode, Walk};
+use crate::vm::{BoundsChecks, CellWidth, EofPolicy, TapePolicy, VMConfig, VMInterface, IO, BF_GET_ERROR, JIT_EXIT_OK, JIT_EXIT_OVERFLOW, JIT_EXIT_IO_ERROR, JIT_EXIT_FUEL_EXHAUSTED, JIT_EXIT_CANCELLED, JIT_EXIT_NEED_INPUT, JIT_EXIT_OUTPUT_FULL, BF_GET_BLOCKED, CancelHandle, Stop, bf_put, bf_get, bf_scan, bf_write, bf_suspend, bf_resume, count_resume_points, exit_status, resume_status};
+use crate::vm::guard::JitTape;
 
-type JITFunc = fn(*mut u8, *mut IO) -> i64;
+#[derive(Error, Debug)]
+enum CraneliftError {
+    #[error("Cranelift error: run without compile (please compile first)")]
+    RunWithoutCompile,
+}
 
 struct JITContext {
     // cranelift jit
@@ -17,12 +24,24 @@ struct JITContext {
     ctx: codegen::Context,
     ir: String,
     // context
-    memory: Vec<u8>,
+    cell_width: CellWidth,
+    tape_len: usize,
+    tape_policy: TapePolicy,
+    origin: usize,
+    eof: EofPolicy,
+    bounds_checks: BoundsChecks,
+    fuel: Option<u64>,
+    output_chunk: Option<usize>,
+    cancel: CancelHandle,
+    memory: JitTape,
     io: IO,
 }
 
 impl JITContext {
-    fn new(input: Box<dyn Read>, output: Box<dyn Write>) -> anyhow::Result<Self> {
+    fn new(input: Box<dyn Read>, output: Box<dyn Write>, config: VMConfig, ir: &[BrainfuckNode]) -> anyhow::Result<Self> {
+        // the pointer is kept in an i32
+        config.check_tape("the Cranelift JIT", &[TapePolicy::Fixed, TapePolicy::Wrap], i32::MAX as usize)?;
+
         let mut flag_builder = settings::builder();
         flag_builder.set("opt_level", "speed_and_size")?;
 
@@ -35,6 +54,10 @@ impl JITContext {
         let mut builder = JITBuilder::with_isa(isa, cranelift_module::default_libcall_names());
         builder.symbol("bf_put", bf_put as *const u8);
         builder.symbol("bf_get", bf_get as *const u8);
+        builder.symbol("bf_scan", bf_scan as *const u8);
+        builder.symbol("bf_write", bf_write as *const u8);
+        builder.symbol("bf_suspend", bf_suspend as *const u8);
+        builder.symbol("bf_resume", bf_resume as *const u8);
 
         // create JITModule
         let module = JITModule::new(builder);
@@ -44,15 +67,21 @@ impl JITContext {
             builder_ctx: FunctionBuilderContext::new(),
             ctx: codegen::Context::new(),
             ir: String::new(),
-            memory: vec![0; MEMORY_SIZE],
-            io: IO {
-                input,
-                output,
-            },
+            cell_width: config.cell_width,
+            tape_len: config.tape_len(),
+            tape_policy: config.tape_policy,
+            origin: config.origin(),
+            eof: config.eof,
+            bounds_checks: config.bounds_checks,
+            fuel: config.fuel,
+            output_chunk: config.output_chunk,
+            cancel: CancelHandle::default(),
+            memory: JitTape::new(&config, ir)?,
+            io: IO::new(input, output),
         })
     }
 
-    fn compile_brainfuck_ir(&mut self, ir: &[BrainfuckIR]) -> anyhow::Result<FuncId> {
+    fn compile_brainfuck_ir(&mut self, ir: &[BrainfuckNode]) -> anyhow::Result<FuncId> {
         // clean ctx
         self.ctx.clear();
 
@@ -75,10 +104,11 @@ impl JITContext {
         {
             let mut func_ctx = FunctionBuilder::new(&mut self.ctx.func, &mut self.builder_ctx);
 
-            // register import func: bf_put(*mut JITContext, u8)
+            // register import func: bf_put(*mut JITContext, u8) -> i64
             let mut put_sig = self.module.make_signature();
             put_sig.params.push(AbiParam::new(types::I64));
             put_sig.params.push(AbiParam::new(types::I32));
+            put_sig.returns.push(AbiParam::new(types::I64));
             let put_func_id = self.module.declare_function(
                 "bf_put",
                 Linkage::Import,
@@ -86,7 +116,7 @@ impl JITContext {
             )?;
             let put_func_ref = self.module.declare_func_in_func(put_func_id, &mut func_ctx.func);
 
-            // register import func: bf_get(*mut JITContext) -> u8
+            // register import func: bf_get(*mut JITContext) -> i32
             let mut get_sig = self.module.make_signature();
             get_sig.params.push(AbiParam::new(types::I64));
             get_sig.returns.push(AbiParam::new(types::I32));
@@ -97,6 +127,60 @@ impl JITContext {
             )?;
             let get_sig_ref = self.module.declare_func_in_func(get_sig_id, &mut func_ctx.func);
 
+            // register import func: bf_scan(*const u8, usize, usize, isize, usize, bool) -> isize
+            let mut scan_sig = self.module.make_signature();
+            scan_sig.params.push(AbiParam::new(types::I64));
+            scan_sig.params.push(AbiParam::new(types::I64));
+            scan_sig.params.push(AbiParam::new(types::I64));
+            scan_sig.params.push(AbiParam::new(types::I64));
+            scan_sig.params.push(AbiParam::new(types::I64));
+            scan_sig.params.push(AbiParam::new(types::I8));
+            scan_sig.returns.push(AbiParam::new(types::I64));
+            let scan_func_id = self.module.declare_function(
+                "bf_scan",
+                Linkage::Import,
+                &scan_sig
+            )?;
+            let scan_func_ref = self.module.declare_func_in_func(scan_func_id, func_ctx.func);
+
+            // register import func: bf_write(*mut JITContext, *const u8, usize) -> i64
+            let mut write_sig = self.module.make_signature();
+            write_sig.params.push(AbiParam::new(types::I64));
+            write_sig.params.push(AbiParam::new(types::I64));
+            write_sig.params.push(AbiParam::new(types::I64));
+            write_sig.returns.push(AbiParam::new(types::I64));
+            let write_func_id = self.module.declare_function(
+                "bf_write",
+                Linkage::Import,
+                &write_sig
+            )?;
+            let write_func_ref = self.module.declare_func_in_func(write_func_id, func_ctx.func);
+
+            // register import func: bf_suspend(*mut JITContext, i64, i64, i64)
+            let mut suspend_sig = self.module.make_signature();
+            suspend_sig.params.push(AbiParam::new(types::I64));
+            suspend_sig.params.push(AbiParam::new(types::I64));
+            suspend_sig.params.push(AbiParam::new(types::I64));
+            suspend_sig.params.push(AbiParam::new(types::I64));
+            let suspend_func_id = self.module.declare_function(
+                "bf_suspend",
+                Linkage::Import,
+                &suspend_sig
+            )?;
+            let suspend_func_ref = self.module.declare_func_in_func(suspend_func_id, func_ctx.func);
+
+            // register import func: bf_resume(*mut JITContext, *mut i64) -> i64
+            let mut resume_sig = self.module.make_signature();
+            resume_sig.params.push(AbiParam::new(types::I64));
+            resume_sig.params.push(AbiParam::new(types::I64));
+            resume_sig.returns.push(AbiParam::new(types::I64));
+            let resume_func_id = self.module.declare_function(
+                "bf_resume",
+                Linkage::Import,
+                &resume_sig
+            )?;
+            let resume_func_ref = self.module.declare_func_in_func(resume_func_id, func_ctx.func);
+
             // create entry block
             let entry_block = func_ctx.create_block();
             func_ctx.append_block_params_for_function_params(entry_block);
@@ -109,19 +193,86 @@ impl JITContext {
             let memory_ptr = func_ctx.block_params(entry_block)[0];
             let context_ptr = func_ctx.block_params(entry_block)[1];
 
-            // declare a variable representing memory offset & init with 0
+            // declare a variable representing memory offset & init with the start cell
             let pointer_var = Variable::from_u32(0);
             func_ctx.declare_var(pointer_var, types::I32);
             {
-                let zero = func_ctx.ins().iconst(types::I32, 0);
-                func_ctx.def_var(pointer_var, zero);
+                let origin = func_ctx.ins().iconst(types::I32, self.origin as i64);
+                func_ctx.def_var(pointer_var, origin);
             }
 
-            // generate cranelift ir
-            codegen_bf_block(&mut func_ctx, &memory_ptr, &pointer_var, ir, &put_func_ref, &get_sig_ref, &context_ptr)?;
+            // loop iterations left, if they are limited
+            let fuel_var = self.fuel.map(|fuel| {
+                let fuel_var = Variable::from_u32(1);
+                func_ctx.declare_var(fuel_var, types::I64);
+                let fuel = func_ctx.ins().iconst(types::I64, fuel as i64);
+                func_ctx.def_var(fuel_var, fuel);
+                fuel_var
+            });
+
+            // the flag of the cancel handle, it outlives the compiled code
+            let cancelled = func_ctx.ins().iconst(self.module.target_config().pointer_type(), self.cancel.flag() as i64);
+
+            // every exit goes through this block, its parameter is the return code
+            let exit_block = func_ctx.create_block();
+            func_ctx.append_block_param(exit_block, types::I64);
+
+            // a run suspended at the n-th `,` or `.` continues at the n-th resume block, which
+            // restores the pointer and fuel bf_resume put in `saved`
+            let saved = func_ctx.create_sized_stack_slot(StackSlotData::new(StackSlotKind::ExplicitSlot, 16, 3));
+            let resume_blocks: Vec<Block> = (0..count_resume_points(ir)).map(|_| func_ctx.create_block()).collect();
+            if !resume_blocks.is_empty() {
+                let saved_addr = func_ctx.ins().stack_addr(types::I64, saved, 0);
+                let call = func_ctx.ins().call(resume_func_ref, &[context_ptr, saved_addr]);
+                let point = func_ctx.inst_results(call)[0];
+                let start_block = func_ctx.create_block();
+                let mut switch = Switch::new();
+                for (n, &block) in resume_blocks.iter().enumerate() {
+                    switch.set_entry(n as u128 + 1, block);
+                }
+                switch.emit(&mut func_ctx, point, start_block);
+                func_ctx.switch_to_block(start_block);
+                func_ctx.seal_block(start_block);
+            }
 
-            // return void
-            func_ctx.ins().return_(&[Value::from_u32(0)]);
+            // generate cranelift ir
+            let cg = Codegen {
+                memory_ptr,
+                context_ptr,
+                pointer_var,
+                fuel_var,
+                cancelled,
+                saved,
+                resume_blocks,
+                next_point: Cell::new(0),
+                suspend_func_ref,
+                put_func_ref,
+                get_func_ref: get_sig_ref,
+                scan_func_ref,
+                write_func_ref,
+                exit_block,
+                cell_width: self.cell_width,
+                tape_len: self.tape_len,
+                wrap: self.tape_policy == TapePolicy::Wrap,
+                // a wrapping pointer never leaves the tape
+                checked: self.bounds_checks == BoundsChecks::Explicit && self.tape_policy == TapePolicy::Fixed,
+                eof: self.eof,
+                cell_type: match self.cell_width {
+                    CellWidth::U8 => types::I8,
+                    CellWidth::U16 => types::I16,
+                    CellWidth::U32 => types::I32,
+                },
+            };
+            codegen_bf_block(&mut func_ctx, &mut self.module, &cg, ir)?;
+
+            // return JIT_EXIT_OK
+            let ok = func_ctx.ins().iconst(types::I64, JIT_EXIT_OK);
+            func_ctx.ins().jump(exit_block, &[ok]);
+
+            func_ctx.switch_to_block(exit_block);
+            func_ctx.seal_block(exit_block);
+            let code = func_ctx.block_params(exit_block)[0];
+            func_ctx.ins().return_(&[code]);
             func_ctx.finalize();
         }
 
@@ -139,97 +290,433 @@ impl JITContext {
     }
 }
 
+/// Values and functions shared by every block of `bf_jit_main`.
+struct Codegen {
+    memory_ptr: Value,
+    context_ptr: Value,
+    pointer_var: Variable,
+    fuel_var: Option<Variable>,
+    /// address of the flag set by `CancelHandle::cancel`
+    cancelled: Value,
+    /// the pointer and fuel of a resumed run
+    saved: StackSlot,
+    /// where each `,` or `.` is resumed, in the order they are generated
+    resume_blocks: Vec<Block>,
+    next_point: Cell<usize>,
+    suspend_func_ref: FuncRef,
+    put_func_ref: FuncRef,
+    get_func_ref: FuncRef,
+    scan_func_ref: FuncRef,
+    write_func_ref: FuncRef,
+    exit_block: Block,
+    cell_width: CellWidth,
+    cell_type: Type,
+    tape_len: usize,
+    /// the pointer wraps around at the ends of the tape
+    wrap: bool,
+    /// pointers are checked against the ends of the tape
+    checked: bool,
+    eof: EofPolicy,
+}
+
+impl Codegen {
+    /// Address of the cell at index `pointer`.
+    fn cell_addr(&self, func_ctx: &mut FunctionBuilder, pointer: Value) -> Value {
+        // a pointer left of the tape points into the guard pages, if there are any
+        let offset_i64 = func_ctx.ins().sextend(types::I64, pointer);
+        let offset_bytes = func_ctx.ins().imul_imm(offset_i64, self.cell_width.bytes() as i64);
+        func_ctx.ins().iadd(self.memory_ptr, offset_bytes)
+    }
+
+    /// Base address and byte offset of the cell `offset` cells away from the current one.
+    fn cell(&self, func_ctx: &mut FunctionBuilder, offset: i32) -> (Value, i32) {
+        let pointer = func_ctx.use_var(self.pointer_var);
+        if self.wrap && offset != 0 {
+            // the cell may be across the end of the tape, compute its index
+            let index = self.wrapping_add(func_ctx, pointer, i64::from(offset));
+            return (self.cell_addr(func_ctx, index), 0);
+        }
+        if self.checked && offset != 0 {
+            self.check_bounds(func_ctx, pointer, i64::from(offset));
+        }
+        let addr = self.cell_addr(func_ctx, pointer);
+        (addr, offset.wrapping_mul(self.cell_width.bytes() as i32))
+    }
+
+    /// `pointer + delta` modulo the tape size, `pointer` is on the tape.
+    fn wrapping_add(&self, func_ctx: &mut FunctionBuilder, pointer: Value, delta: i64) -> Value {
+        let len = self.tape_len as i64;
+        // both are below 2^31, so the sum can't overflow the unsigned i32
+        let sum = func_ctx.ins().iadd_imm(pointer, delta.rem_euclid(len));
+        let wrapped = func_ctx.ins().iadd_imm(sum, -len);
+        let past_end = func_ctx.ins().icmp_imm(IntCC::UnsignedGreaterThanOrEqual, sum, len);
+        func_ctx.ins().select(past_end, wrapped, sum)
+    }
+
+    /// Move the pointer by `delta` cells.
+    fn move_pointer(&self, func_ctx: &mut FunctionBuilder, delta: i64) {
+        let old_ptr = func_ctx.use_var(self.pointer_var);
+        let new_ptr = if self.wrap {
+            self.wrapping_add(func_ctx, old_ptr, delta)
+        } else {
+            if self.checked {
+                self.check_bounds(func_ctx, old_ptr, delta);
+            }
+            func_ctx.ins().iadd_imm(old_ptr, delta)
+        };
+        func_ctx.def_var(self.pointer_var, new_ptr);
+    }
+
+    /// Leave `bf_jit_main` with `JIT_EXIT_OVERFLOW` unless `pointer + delta` is on the tape.
+    fn check_bounds(&self, func_ctx: &mut FunctionBuilder, pointer: Value, delta: i64) {
+        // in 64 bits a negative index compares as a huge unsigned one
+        let pointer_i64 = func_ctx.ins().uextend(types::I64, pointer);
+        let index = func_ctx.ins().iadd_imm(pointer_i64, delta);
+        let off_tape = func_ctx.ins().icmp_imm(IntCC::UnsignedGreaterThanOrEqual, index, self.tape_len as i64);
+
+        let on_tape = func_ctx.create_block();
+        let code = func_ctx.ins().iconst(types::I64, JIT_EXIT_OVERFLOW);
+        func_ctx.ins().brif(off_tape, self.exit_block, &[code], on_tape, &[]);
+        func_ctx.switch_to_block(on_tape);
+        func_ctx.seal_block(on_tape);
+    }
+
+    /// Take a unit of fuel, leave `bf_jit_main` with `JIT_EXIT_FUEL_EXHAUSTED` if there is
+    /// none left.
+    fn spend_fuel(&self, func_ctx: &mut FunctionBuilder) {
+        let Some(fuel_var) = self.fuel_var else {
+            return;
+        };
+        // a limit above i64::MAX is negative here, it still counts down to 0
+        let fuel = func_ctx.use_var(fuel_var);
+        let fueled_block = func_ctx.create_block();
+        let code = func_ctx.ins().iconst(types::I64, JIT_EXIT_FUEL_EXHAUSTED);
+        func_ctx.ins().brif(fuel, fueled_block, &[], self.exit_block, &[code]);
+        func_ctx.switch_to_block(fueled_block);
+        func_ctx.seal_block(fueled_block);
+        let rest = func_ctx.ins().iadd_imm(fuel, -1);
+        func_ctx.def_var(fuel_var, rest);
+    }
+
+    /// Leave `bf_jit_main` with `JIT_EXIT_CANCELLED` if the run has been cancelled.
+    fn poll_cancel(&self, func_ctx: &mut FunctionBuilder) {
+        // atomic so the load is redone on every iteration
+        let cancelled = func_ctx.ins().atomic_load(types::I8, MemFlags::trusted(), self.cancelled);
+        let running_block = func_ctx.create_block();
+        let code = func_ctx.ins().iconst(types::I64, JIT_EXIT_CANCELLED);
+        func_ctx.ins().brif(cancelled, self.exit_block, &[code], running_block, &[]);
+        func_ctx.switch_to_block(running_block);
+        func_ctx.seal_block(running_block);
+    }
+
+    /// Start the block the next `,`, or the code after the next `.`, runs in, a run suspended
+    /// there is resumed at its start. Returns the number `bf_suspend` saves for it.
+    fn resume_point(&self, func_ctx: &mut FunctionBuilder) -> i64 {
+        let n = self.next_point.get();
+        self.next_point.set(n + 1);
+        let read_block = func_ctx.create_block();
+        func_ctx.ins().jump(read_block, &[]);
+
+        // restore the pointer and fuel, the code continues as if it hadn't stopped
+        let resume_block = self.resume_blocks[n];
+        func_ctx.switch_to_block(resume_block);
+        func_ctx.seal_block(resume_block);
+        let pointer = func_ctx.ins().stack_load(types::I64, self.saved, 0);
+        let pointer = func_ctx.ins().ireduce(types::I32, pointer);
+        func_ctx.def_var(self.pointer_var, pointer);
+        if let Some(fuel_var) = self.fuel_var {
+            let fuel = func_ctx.ins().stack_load(types::I64, self.saved, 8);
+            func_ctx.def_var(fuel_var, fuel);
+        }
+        func_ctx.ins().jump(read_block, &[]);
+
+        func_ctx.switch_to_block(read_block);
+        func_ctx.seal_block(read_block);
+        n as i64 + 1
+    }
+
+    /// Save the pointer and fuel for resuming at `point` and leave `bf_jit_main` with `code`,
+    /// `JIT_EXIT_NEED_INPUT` or `JIT_EXIT_OUTPUT_FULL`.
+    fn suspend(&self, func_ctx: &mut FunctionBuilder, point: i64, code: i64) {
+        let point = func_ctx.ins().iconst(types::I64, point);
+        let pointer = func_ctx.use_var(self.pointer_var);
+        let pointer = func_ctx.ins().sextend(types::I64, pointer);
+        let fuel = match self.fuel_var {
+            Some(fuel_var) => func_ctx.use_var(fuel_var),
+            None => func_ctx.ins().iconst(types::I64, 0),
+        };
+        func_ctx.ins().call(self.suspend_func_ref, &[self.context_ptr, point, pointer, fuel]);
+        let code = func_ctx.ins().iconst(types::I64, code);
+        func_ctx.ins().jump(self.exit_block, &[code]);
+    }
+
+    /// Continue after `bf_put` or `bf_write` returned `code`. A write that filled the output
+    /// suspends the run, it is resumed right after the write.
+    fn after_write(&self, func_ctx: &mut FunctionBuilder, code: Value) {
+        let full_block = func_ctx.create_block();
+        let check_block = func_ctx.create_block();
+        let full = func_ctx.ins().icmp_imm(IntCC::Equal, code, JIT_EXIT_OUTPUT_FULL);
+        func_ctx.ins().brif(full, full_block, &[], check_block, &[]);
+        func_ctx.switch_to_block(check_block);
+        func_ctx.seal_block(check_block);
+        self.exit_unless_ok(func_ctx, code);
+        let point = self.resume_point(func_ctx);
+        let next_block = func_ctx.current_block().expect("resume_point starts a block");
+
+        func_ctx.switch_to_block(full_block);
+        func_ctx.seal_block(full_block);
+        self.suspend(func_ctx, point, JIT_EXIT_OUTPUT_FULL);
+        func_ctx.switch_to_block(next_block);
+    }
+
+    /// Leave `bf_jit_main` with `code` unless it is `JIT_EXIT_OK`.
+    fn exit_unless_ok(&self, func_ctx: &mut FunctionBuilder, code: Value) {
+        let ok_block = func_ctx.create_block();
+        func_ctx.ins().brif(code, self.exit_block, &[code], ok_block, &[]);
+        func_ctx.switch_to_block(ok_block);
+        func_ctx.seal_block(ok_block);
+    }
+
+    /// An IR constant truncated to the cell width.
+    fn cell_const(&self, func_ctx: &mut FunctionBuilder, n: u32) -> Value {
+        func_ctx.ins().iconst(self.cell_type, i64::from(self.cell_width.truncate(n)))
+    }
+}
+
 fn codegen_bf_block(
     func_ctx: &mut FunctionBuilder,
-    memory_ptr: &Value,
-    pointer_var: &Variable,
-    ir_block: &[BrainfuckIR],
-    put_func_ref: &FuncRef,
-    get_func_ref: &FuncRef,
-    context_ptr: &Value,
+    module: &mut JITModule,
+    cg: &Codegen,
+    ir: &[BrainfuckNode],
 ) -> anyhow::Result<()> {
-    for inst in ir_block {
-        match inst {
-            BrainfuckIR::AddVal(n) => {
-                // get memory offset
-                let offset_i32 = func_ctx.use_var(*pointer_var);
-                let offset_i64 = func_ctx.ins().uextend(types::I64, offset_i32);
-                let mem = func_ctx.ins().iadd(*memory_ptr, offset_i64);
+    // the head, body and end block of every loop being generated, innermost last
+    let mut loops = Vec::new();
+    for step in walk(ir) {
+        let node = match step {
+            Walk::Enter(node) => node,
+            Walk::Exit(_) => {
+                let (loop_head, loop_body, loop_end) = loops.pop().expect("every loop is entered before it ends");
+
+                // at the end of loop: pay for the iteration, stop if cancelled and jump back
+                // to loop_head
+                cg.spend_fuel(func_ctx);
+                cg.poll_cancel(func_ctx);
+                func_ctx.ins().jump(loop_head, &[]);
+
+                // switch to loop_end
+                func_ctx.switch_to_block(loop_end);
 
-                // load a byte from memory
+                // seal all blocks
+                func_ctx.seal_block(loop_head);
+                func_ctx.seal_block(loop_body);
+                func_ctx.seal_block(loop_end);
+                continue;
+            }
+        };
+        // tag the generated instructions with the source offset
+        func_ctx.set_srcloc(SourceLoc::new(node.span.start.offset as u32));
+        match &node.ir {
+            BrainfuckIR::AddVal(n, offset) => {
+                // get memory address
+                let (mem, imm) = cg.cell(func_ctx, *offset);
+
+                // load a cell from memory
                 let mem_flags = MemFlags::new();
-                let old_val = func_ctx.ins().load(types::I8, mem_flags, mem, 0);
-                let old_val32 = func_ctx.ins().uextend(types::I32, old_val);
+                let old_val = func_ctx.ins().load(cg.cell_type, mem_flags, mem, imm);
 
-                // add the immediate value n
-                let new_val32 = func_ctx.ins().iadd_imm(old_val32, i64::from(*n));
+                // add the constant n, wrapping at the cell width
+                let n = cg.cell_const(func_ctx, *n);
+                let new_val = func_ctx.ins().iadd(old_val, n);
 
                 // store new value to memory
-                let new_val8 = func_ctx.ins().ireduce(types::I8, new_val32);
-                func_ctx.ins().store(mem_flags, new_val8, mem, 0);
+                func_ctx.ins().store(mem_flags, new_val, mem, imm);
             }
 
-            BrainfuckIR::SubVal(n) => {
-                // get memory offset
-                let offset_i32 = func_ctx.use_var(*pointer_var);
-                let offset_i64 = func_ctx.ins().uextend(types::I64, offset_i32);
-                let mem = func_ctx.ins().iadd(*memory_ptr, offset_i64);
+            BrainfuckIR::SubVal(n, offset) => {
+                // get memory address
+                let (mem, imm) = cg.cell(func_ctx, *offset);
 
-                // load a byte from memory
+                // load a cell from memory
                 let mem_flags = MemFlags::new();
-                let old_val = func_ctx.ins().load(types::I8, mem_flags, mem, 0);
-                let old_val32 = func_ctx.ins().uextend(types::I32, old_val);
+                let old_val = func_ctx.ins().load(cg.cell_type, mem_flags, mem, imm);
 
-                // subtract the immediate value n
-                let new_val32 = func_ctx.ins().iadd_imm(old_val32, -i64::from(*n));
+                // subtract the constant n, wrapping at the cell width
+                let n = cg.cell_const(func_ctx, *n);
+                let new_val = func_ctx.ins().isub(old_val, n);
 
                 // store new value to memory
-                let new_val8 = func_ctx.ins().ireduce(types::I8, new_val32);
-                func_ctx.ins().store(mem_flags, new_val8, mem, 0);
+                func_ctx.ins().store(mem_flags, new_val, mem, imm);
+            }
+
+            BrainfuckIR::SetVal(n, offset) => {
+                // get memory address
+                let (mem, imm) = cg.cell(func_ctx, *offset);
+
+                // store the constant, the old value is never loaded
+                let new_val = cg.cell_const(func_ctx, *n);
+                func_ctx.ins().store(MemFlags::new(), new_val, mem, imm);
+            }
+
+            BrainfuckIR::MulAdd { src, offset, factor } => {
+                // load the counter
+                let (src_mem, src_imm) = cg.cell(func_ctx, *src);
+                let mem_flags = MemFlags::new();
+                let val = func_ctx.ins().load(cg.cell_type, mem_flags, src_mem, src_imm);
+
+                // the loop this came from never ran on a zero counter, so the target may
+                // be off the tape and is only touched once the counter isn't zero
+                let done_block = (!cg.wrap).then(|| {
+                    let add_block = func_ctx.create_block();
+                    let done_block = func_ctx.create_block();
+                    func_ctx.ins().brif(val, add_block, &[], done_block, &[]);
+                    func_ctx.switch_to_block(add_block);
+                    func_ctx.seal_block(add_block);
+                    done_block
+                });
+
+                // load the target cell
+                let (mem, imm) = cg.cell(func_ctx, *offset);
+                let target = func_ctx.ins().load(cg.cell_type, mem_flags, mem, imm);
+
+                // target += counter * factor
+                let factor = cg.cell_const(func_ctx, *factor);
+                let product = func_ctx.ins().imul(val, factor);
+                let new_val = func_ctx.ins().iadd(target, product);
+
+                // store new value to the target cell
+                func_ctx.ins().store(mem_flags, new_val, mem, imm);
+
+                if let Some(done_block) = done_block {
+                    func_ctx.ins().jump(done_block, &[]);
+                    func_ctx.switch_to_block(done_block);
+                    func_ctx.seal_block(done_block);
+                }
             }
 
             BrainfuckIR::PtrMovRight(n) => {
                 // memory offset += n
-                let old_ptr = func_ctx.use_var(*pointer_var);
-                let new_ptr = func_ctx.ins().iadd_imm(old_ptr, i64::from(*n));
-                func_ctx.def_var(*pointer_var, new_ptr);
+                cg.move_pointer(func_ctx, i64::from(*n));
             }
 
             BrainfuckIR::PtrMovLeft(n) => {
                 // memory offset -= n
-                let old_ptr = func_ctx.use_var(*pointer_var);
-                let new_ptr = func_ctx.ins().iadd_imm(old_ptr, -i64::from(*n));
-                func_ctx.def_var(*pointer_var, new_ptr);
+                cg.move_pointer(func_ctx, -i64::from(*n));
             }
 
-            BrainfuckIR::PutByte => {
-                // load a byte from memory
-                let offset_i32 = func_ctx.use_var(*pointer_var);
+            BrainfuckIR::ScanRight(n) | BrainfuckIR::ScanLeft(n) => {
+                let stride = match &node.ir {
+                    BrainfuckIR::ScanRight(_) => i64::from(*n),
+                    _ => -i64::from(*n),
+                };
+
+                // call bf_scan(memory, len, ptr, stride, width, wrap)
+                let offset_i32 = func_ctx.use_var(cg.pointer_var);
                 let offset_i64 = func_ctx.ins().uextend(types::I64, offset_i32);
-                let mem = func_ctx.ins().iadd(*memory_ptr, offset_i64);
-                let val_i8 = func_ctx.ins().load(types::I8, MemFlags::new(), mem, 0);
-                let val_i32 = func_ctx.ins().uextend(types::I32, val_i8);
+                let len = func_ctx.ins().iconst(types::I64, cg.tape_len as i64);
+                let stride = func_ctx.ins().iconst(types::I64, stride);
+                let width = func_ctx.ins().iconst(types::I64, cg.cell_width.bytes() as i64);
+                let wrap = func_ctx.ins().iconst(types::I8, i64::from(cg.wrap));
+                let call = func_ctx.ins().call(cg.scan_func_ref, &[cg.memory_ptr, len, offset_i64, stride, width, wrap]);
+                let found = func_ctx.inst_results(call)[0];
+
+                // a negative result is the negated exit code
+                let found_block = func_ctx.create_block();
+                let failed_block = func_ctx.create_block();
+                let failed = func_ctx.ins().icmp_imm(IntCC::SignedLessThan, found, 0);
+                func_ctx.ins().brif(failed, failed_block, &[], found_block, &[]);
+
+                func_ctx.switch_to_block(failed_block);
+                func_ctx.seal_block(failed_block);
+                let code = func_ctx.ins().ineg(found);
+                func_ctx.ins().jump(cg.exit_block, &[code]);
+
+                func_ctx.switch_to_block(found_block);
+                func_ctx.seal_block(found_block);
+                let new_ptr = func_ctx.ins().ireduce(types::I32, found);
+                func_ctx.def_var(cg.pointer_var, new_ptr);
+            }
+
+            BrainfuckIR::PutByte(offset) => {
+                // load a cell from memory
+                let (mem, imm) = cg.cell(func_ctx, *offset);
+                let val = func_ctx.ins().load(cg.cell_type, MemFlags::new(), mem, imm);
+                let val_i32 = if cg.cell_type == types::I32 {
+                    val
+                } else {
+                    func_ctx.ins().uextend(types::I32, val)
+                };
+
+                // call bf_put, it writes the low byte
+                let call = func_ctx.ins().call(cg.put_func_ref, &[cg.context_ptr, val_i32]);
+                let code = func_ctx.inst_results(call)[0];
+                cg.after_write(func_ctx, code);
+            }
 
-                // call bf_put
-                let call = func_ctx.ins().call(*put_func_ref, &[*context_ptr, val_i32]);
-                let _ = func_ctx.inst_results(call);
+            BrainfuckIR::PutBytes(bytes) => {
+                // the bytes live in a read-only data object next to the code
+                let data_id = module.declare_anonymous_data(false, false)?;
+                let mut data = DataDescription::new();
+                data.define(bytes.clone().into_boxed_slice());
+                module.define_data(data_id, &data)?;
+                let data_gv = module.declare_data_in_func(data_id, func_ctx.func);
+                let data_ptr = func_ctx.ins().global_value(types::I64, data_gv);
+
+                // call bf_write
+                let len = func_ctx.ins().iconst(types::I64, bytes.len() as i64);
+                let call = func_ctx.ins().call(cg.write_func_ref, &[cg.context_ptr, data_ptr, len]);
+                let code = func_ctx.inst_results(call)[0];
+                cg.after_write(func_ctx, code);
             }
 
-            BrainfuckIR::GetByte => {
-                // call bf_get
-                let call = func_ctx.ins().call(*get_func_ref, &[*context_ptr]);
+            BrainfuckIR::GetByte(offset) => {
+                let point = cg.resume_point(func_ctx);
+                let (mem, imm) = cg.cell(func_ctx, *offset);
+
+                // call bf_get, it returns -1 at the end of input, BF_GET_ERROR if reading failed
+                // and BF_GET_BLOCKED if it would block
+                let call = func_ctx.ins().call(cg.get_func_ref, &[cg.context_ptr]);
                 let results = func_ctx.inst_results(call);
                 let val_i32 = results[0];
-
-                // store to memory
-                let val_i8 = func_ctx.ins().ireduce(types::I8, val_i32);
-                let offset_i32 = func_ctx.use_var(*pointer_var);
-                let offset_i64 = func_ctx.ins().uextend(types::I64, offset_i32);
-                let mem = func_ctx.ins().iadd(*memory_ptr, offset_i64);
-                func_ctx.ins().store(MemFlags::new(), val_i8, mem, 0);
+                let failed = func_ctx.ins().icmp_imm(IntCC::Equal, val_i32, i64::from(BF_GET_ERROR));
+                let read_block = func_ctx.create_block();
+                let code = func_ctx.ins().iconst(types::I64, JIT_EXIT_IO_ERROR);
+                func_ctx.ins().brif(failed, cg.exit_block, &[code], read_block, &[]);
+                func_ctx.switch_to_block(read_block);
+                func_ctx.seal_block(read_block);
+
+                let blocked = func_ctx.ins().icmp_imm(IntCC::Equal, val_i32, i64::from(BF_GET_BLOCKED));
+                let suspend_block = func_ctx.create_block();
+                let got_block = func_ctx.create_block();
+                func_ctx.ins().brif(blocked, suspend_block, &[], got_block, &[]);
+                func_ctx.switch_to_block(suspend_block);
+                func_ctx.seal_block(suspend_block);
+                cg.suspend(func_ctx, point, JIT_EXIT_NEED_INPUT);
+                func_ctx.switch_to_block(got_block);
+                func_ctx.seal_block(got_block);
+
+                let is_eof = func_ctx.ins().icmp_imm(IntCC::SignedLessThan, val_i32, 0);
+
+                // truncated to the cell width the byte is zero-extended and -1 has every bit set
+                let read = if cg.cell_type == types::I32 {
+                    val_i32
+                } else {
+                    func_ctx.ins().ireduce(cg.cell_type, val_i32)
+                };
+                let val = match cg.eof {
+                    EofPolicy::Zero => {
+                        let zero = cg.cell_const(func_ctx, 0);
+                        func_ctx.ins().select(is_eof, zero, read)
+                    }
+                    EofPolicy::MinusOne => read,
+                    EofPolicy::Unchanged => {
+                        let old = func_ctx.ins().load(cg.cell_type, MemFlags::new(), mem, imm);
+                        func_ctx.ins().select(is_eof, old, read)
+                    }
+                };
+                func_ctx.ins().store(MemFlags::new(), val, mem, imm);
             }
 
-            BrainfuckIR::Loop(loop_ir) => {
+            BrainfuckIR::Loop(_) => {
                 // create blocks
                 let loop_head = func_ctx.create_block(); // judgment logic for loop
                 let loop_body = func_ctx.create_block(); // loop body
@@ -242,30 +729,15 @@ fn codegen_bf_block(
                 func_ctx.switch_to_block(loop_head);
 
                 // load a value from memory
-                let offset_i32 = func_ctx.use_var(*pointer_var);
-                let offset_i64 = func_ctx.ins().uextend(types::I64, offset_i32);
-                let mem = func_ctx.ins().iadd(*memory_ptr, offset_i64);
-                let val_i8 = func_ctx.ins().load(types::I8, MemFlags::new(), mem, 0);
-                let val_i32 = func_ctx.ins().uextend(types::I32, val_i8);
+                let (mem, imm) = cg.cell(func_ctx, 0);
+                let val = func_ctx.ins().load(cg.cell_type, MemFlags::new(), mem, imm);
 
                 // brif: if value != 0 { loop_body } else { loop_end }
-                func_ctx.ins().brif(val_i32, loop_body, &[], loop_end, &[]);
+                func_ctx.ins().brif(val, loop_body, &[], loop_end, &[]);
 
-                // switch to loop_body
+                // switch to loop_body, its instructions follow up to the `Exit` of the loop
                 func_ctx.switch_to_block(loop_body);
-
-                // generate loop body instructions recursively
-                codegen_bf_block(func_ctx, memory_ptr, pointer_var, loop_ir, put_func_ref, get_func_ref, context_ptr)?;
-                // at the end of loop: jump back to loop_head
-                func_ctx.ins().jump(loop_head, &[]);
-
-                // switch to loop_end
-                func_ctx.switch_to_block(loop_end);
-
-                // seal all blocks
-                func_ctx.seal_block(loop_head);
-                func_ctx.seal_block(loop_body);
-                func_ctx.seal_block(loop_end);
+                loops.push((loop_head, loop_body, loop_end));
             }
         }
     }
@@ -274,42 +746,52 @@ fn codegen_bf_block(
 }
 
 pub struct VMCranelift {
-    ir: Vec<BrainfuckIR>,
+    ir: Vec<BrainfuckNode>,
     context: JITContext,
     func: *const u8,
 }
 
 impl VMInterface for VMCranelift {
-    fn new(
-        ir: Vec<BrainfuckIR>,
+    fn with_config(
+        ir: Vec<BrainfuckNode>,
         input: Box<dyn Read>,
         output: Box<dyn Write>,
+        config: VMConfig,
     ) -> anyhow::Result<Self> {
         Ok(Self {
+            context: JITContext::new(input, output, config, &ir)?,
             ir,
-            context: JITContext::new(input, output)?,
             func: std::ptr::null(),
         })
     }
 
     fn run(&mut self) -> anyhow::Result<Duration> {
-        // create func: fn(mem: *mut u8, ctx: *mut IO<'io>) -> i64
-        let func = unsafe {
-            std::mem::transmute::<_, JITFunc>(self.func)
-        };
-
         let clock = quanta::Clock::new();
 
-        // call func
+        // a suspended run is abandoned
+        self.context.io.suspended = None;
+        self.context.io.limit_output(None);
+        // call func: fn(mem: *mut u8, ctx: *mut IO) -> i64
+        let func = self.func()?;
         let start = clock.now();
-        let _ = func(
-            self.context.memory.as_mut_ptr(),
-            &mut self.context.io,
-        );
+        let code = unsafe { self.context.memory.run(func, &mut self.context.io) };
         let end = clock.now();
+        exit_status(code, &mut self.context.io, self.context.fuel)?;
 
         Ok(end - start)
     }
+
+    fn resume(&mut self) -> anyhow::Result<Stop> {
+        // bf_jit_main continues where bf_suspend left it, if it did
+        let func = self.func()?;
+        self.context.io.limit_output(self.context.output_chunk);
+        let code = unsafe { self.context.memory.run(func, &mut self.context.io) };
+        resume_status(code, &mut self.context.io, self.context.fuel)
+    }
+
+    fn cancel_handle(&self) -> CancelHandle {
+        self.context.cancel.clone()
+    }
 }
 
 impl VMCranelift {
@@ -327,4 +809,12 @@ impl VMCranelift {
     pub fn get_ir(&self) -> String {
         self.context.ir.clone()
     }
+
+    /// The compiled `bf_jit_main`, null until `compile`.
+    fn func(&self) -> Result<*const u8, CraneliftError> {
+        if self.func.is_null() {
+            return Err(CraneliftError::RunWithoutCompile);
+        }
+        Ok(self.func)
+    }
 }
@@ -0,0 +1,321 @@
+//! Tapes for the JIT backends.
+//!
+//! With `BoundsChecks::GuardPages` the tape is mapped between inaccessible guard pages
+//! instead of checking the pointer in the generated code. A step off the tape faults on a
+//! guard page, and the fault handler abandons `bf_jit_main` so it returns
+//! `JIT_EXIT_OVERFLOW` like a failed check would.
+
+use crate::ir::{walk, BrainfuckIR, BrainfuckNode, Walk};
+use crate::vm::{alloc_tape, BoundsChecks, ConfigError, TapePolicy, VMConfig, IO};
+
+/// Signature of the compiled `bf_jit_main`.
+type JITFunc = unsafe extern "C" fn(*mut u8, *mut IO) -> i64;
+
+/// The largest guard region on either side of the tape, in bytes.
+const MAX_GUARD_SIZE: u64 = 1 << 30; // 1 GiB
+
+/// The memory compiled code runs on.
+pub(crate) enum JitTape {
+    /// Checked by the code itself, or never left
+    Heap(Vec<u32>),
+    /// Between guard pages
+    Guarded(sys::GuardedTape),
+}
+
+impl JitTape {
+    /// Allocate the tape `config` asks for, the guard pages are made wide enough for `ir`.
+    pub(crate) fn new(config: &VMConfig, ir: &[BrainfuckNode]) -> anyhow::Result<Self> {
+        // a wrapping pointer never leaves the tape
+        if config.bounds_checks != BoundsChecks::GuardPages || config.tape_policy != TapePolicy::Fixed {
+            return Ok(JitTape::Heap(alloc_tape(config.tape_len(), config.cell_width)));
+        }
+
+        let bytes = config.cell_width.bytes() as u64;
+        let reach = max_reach(ir);
+        // a cell `reach` away must lie entirely inside the guard region
+        let guard = reach.checked_add(1).and_then(|cells| cells.checked_mul(bytes))
+            .filter(|&guard| guard <= MAX_GUARD_SIZE)
+            .ok_or(ConfigError::GuardTooLarge { reach })?;
+        let tape = sys::GuardedTape::new(config.tape_len() * bytes as usize, guard as usize)?;
+        Ok(JitTape::Guarded(tape))
+    }
+
+    /// Call `func`, the compiled `bf_jit_main`, on this tape and return its exit code.
+    ///
+    /// # Safety
+    ///
+    /// `func` must be compiled for this tape.
+    pub(crate) unsafe fn run(&mut self, func: *const u8, io: *mut IO) -> i64 {
+        match self {
+            JitTape::Heap(memory) => {
+                let func = std::mem::transmute::<*const u8, JITFunc>(func);
+                func(memory.as_mut_ptr() as *mut u8, io)
+            }
+            JitTape::Guarded(tape) => tape.call(func, io),
+        }
+    }
+}
+
+/// How many cells past either end of the tape `ir` may touch before its first access off
+/// the tape, the width the guard regions need.
+///
+/// Between two accesses the pointer drifts by the moves in between, and an access at
+/// `offset` lands up to the drift plus `|offset|` off the tape. Once an access succeeded the
+/// pointer is at most `|offset|` off the tape.
+pub(crate) fn max_reach(ir: &[BrainfuckNode]) -> u64 {
+    let mut reach = 0;
+    // the drift in every block being walked, innermost last, each block starts on the tape
+    let mut drifts = vec![0u64];
+    for step in walk(ir) {
+        let node = match step {
+            Walk::Enter(node) => node,
+            Walk::Exit(_) => {
+                // the body ends in the check of the loop head
+                let end = drifts.pop().expect("every loop is entered before it ends");
+                access(&mut reach, &mut { end }, 0);
+                *drifts.last_mut().expect("the program is the outermost block") = 0;
+                continue;
+            }
+        };
+        let drift = drifts.last_mut().expect("the program is the outermost block");
+        match &node.ir {
+            BrainfuckIR::AddVal(_, offset)
+            | BrainfuckIR::SubVal(_, offset)
+            | BrainfuckIR::SetVal(_, offset)
+            | BrainfuckIR::PutByte(offset)
+            | BrainfuckIR::GetByte(offset) => access(&mut reach, drift, *offset),
+            BrainfuckIR::MulAdd { src, offset, .. } => {
+                access(&mut reach, drift, *src);
+                // the target is only touched for a counter other than zero
+                access(&mut reach, &mut { *drift }, *offset);
+            }
+            BrainfuckIR::PtrMovRight(n) | BrainfuckIR::PtrMovLeft(n) => *drift += u64::from(*n),
+            // `bf_scan` checks the pointer itself and stops on the tape
+            BrainfuckIR::ScanRight(_) | BrainfuckIR::ScanLeft(_) => *drift = 0,
+            BrainfuckIR::PutBytes(_) => {}
+            BrainfuckIR::Loop(_) => {
+                access(&mut reach, drift, 0);
+                // the body starts right after the check of the loop head
+                drifts.push(0);
+            }
+        }
+    }
+    reach
+}
+
+/// An access `offset` cells away from a pointer `drift` cells off the tape at most.
+fn access(reach: &mut u64, drift: &mut u64, offset: i32) {
+    let offset = u64::from(offset.unsigned_abs());
+    *reach = (*reach).max(*drift + offset);
+    *drift = (*drift).min(offset);
+}
+
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+mod sys {
+    use std::cell::Cell;
+    use std::io;
+    use std::sync::OnceLock;
+    use libc::{c_int, c_void, siginfo_t};
+
+    use crate::vm::{ConfigError, IO, JIT_EXIT_OVERFLOW};
+
+    // bf_guarded_call(func, memory, io, stack) calls func(memory, io) after saving the
+    // callee-saved registers and storing its stack pointer in `stack`. The fault handler
+    // resumes at bf_guarded_return with that stack pointer to abandon the JIT frame.
+    std::arch::global_asm!(
+        ".pushsection .text.bf_guarded_call,\"ax\",@progbits",
+        ".p2align 4",
+        ".globl bf_guarded_call",
+        ".hidden bf_guarded_call",
+        ".type bf_guarded_call,@function",
+        "bf_guarded_call:",
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // keep the stack 16-byte aligned at the call
+        "sub rsp, 8",
+        "mov [rcx], rsp",
+        "mov rax, rdi",
+        "mov rdi, rsi",
+        "mov rsi, rdx",
+        "call rax",
+        ".globl bf_guarded_return",
+        ".hidden bf_guarded_return",
+        "bf_guarded_return:",
+        "add rsp, 8",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+        ".size bf_guarded_call, . - bf_guarded_call",
+        ".popsection",
+    );
+
+    extern "C" {
+        fn bf_guarded_call(func: *const u8, memory: *mut u8, io: *mut c_void, stack: *mut usize) -> i64;
+        fn bf_guarded_return();
+    }
+
+    /// The mapping compiled code is running on in this thread.
+    #[derive(Clone, Copy)]
+    struct Active {
+        start: usize,
+        end: usize,
+        // stack pointer bf_guarded_return expects
+        stack: *const usize,
+    }
+
+    thread_local! {
+        static ACTIVE: Cell<Option<Active>> = const { Cell::new(None) };
+    }
+
+    /// The SIGSEGV handler installed before ours.
+    static PREVIOUS: OnceLock<libc::sigaction> = OnceLock::new();
+
+    /// A tape mapped between two `PROT_NONE` regions.
+    pub(crate) struct GuardedTape {
+        map: *mut u8,
+        map_len: usize,
+        guard: usize,
+    }
+
+    impl GuardedTape {
+        /// Map `tape` bytes with at least `guard` bytes of guard pages on either side.
+        pub(crate) fn new(tape: usize, guard: usize) -> anyhow::Result<Self> {
+            let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            // the ends of the tape have to be page boundaries
+            if !tape.is_multiple_of(page) {
+                return Err(ConfigError::UnalignedTape { page }.into());
+            }
+            let guard = guard.div_ceil(page) * page;
+            let map_len = tape + 2 * guard;
+
+            install_handler()?;
+            // the guard pages are only reserved, they never take up memory
+            let map = unsafe {
+                libc::mmap(
+                    std::ptr::null_mut(),
+                    map_len,
+                    libc::PROT_NONE,
+                    libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                    -1,
+                    0,
+                )
+            };
+            if map == libc::MAP_FAILED {
+                return Err(io::Error::last_os_error().into());
+            }
+            // from here on the mapping is released on drop
+            let tape_map = Self { map: map as *mut u8, map_len, guard };
+            let ret = unsafe { libc::mprotect(tape_map.memory() as *mut c_void, tape, libc::PROT_READ | libc::PROT_WRITE) };
+            if ret != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            Ok(tape_map)
+        }
+
+        /// The first cell of the tape.
+        fn memory(&self) -> *mut u8 {
+            unsafe { self.map.add(self.guard) }
+        }
+
+        /// Call `func` on the tape, a fault on a guard page returns `JIT_EXIT_OVERFLOW`.
+        pub(crate) unsafe fn call(&mut self, func: *const u8, io: *mut IO) -> i64 {
+            let mut stack = 0usize;
+            let stack_ptr = &mut stack as *mut usize;
+            let start = self.map as usize;
+            // IO callbacks may run other programs on this thread
+            let outer = ACTIVE.replace(Some(Active { start, end: start + self.map_len, stack: stack_ptr }));
+            let code = bf_guarded_call(func, self.memory(), io as *mut c_void, stack_ptr);
+            ACTIVE.set(outer);
+            code
+        }
+    }
+
+    impl Drop for GuardedTape {
+        fn drop(&mut self) {
+            unsafe {
+                libc::munmap(self.map as *mut c_void, self.map_len);
+            }
+        }
+    }
+
+    /// Install `on_fault` for SIGSEGV, once per process.
+    fn install_handler() -> io::Result<()> {
+        static INSTALLED: OnceLock<Result<(), i32>> = OnceLock::new();
+        let installed = INSTALLED.get_or_init(|| unsafe {
+            let mut action: libc::sigaction = std::mem::zeroed();
+            action.sa_sigaction = on_fault as *const () as usize;
+            action.sa_flags = libc::SA_SIGINFO | libc::SA_ONSTACK;
+            libc::sigemptyset(&mut action.sa_mask);
+            let mut previous: libc::sigaction = std::mem::zeroed();
+            if libc::sigaction(libc::SIGSEGV, &action, &mut previous) != 0 {
+                return Err(io::Error::last_os_error().raw_os_error().unwrap_or(0));
+            }
+            let _ = PREVIOUS.set(previous);
+            Ok(())
+        });
+        installed.map_err(io::Error::from_raw_os_error)
+    }
+
+    extern "C" fn on_fault(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+        unsafe {
+            let addr = (*info).si_addr() as usize;
+            match ACTIVE.get() {
+                Some(active) if (active.start..active.end).contains(&addr) => {
+                    // resume in bf_guarded_call as if `bf_jit_main` returned the overflow
+                    let gregs = &mut (*(context as *mut libc::ucontext_t)).uc_mcontext.gregs;
+                    gregs[libc::REG_RSP as usize] = *active.stack as i64;
+                    gregs[libc::REG_RIP as usize] = bf_guarded_return as *const () as i64;
+                    gregs[libc::REG_RAX as usize] = JIT_EXIT_OVERFLOW;
+                }
+                _ => forward(signal, info, context),
+            }
+        }
+    }
+
+    /// Hand a fault that isn't ours to the handler installed before.
+    unsafe fn forward(signal: c_int, info: *mut siginfo_t, context: *mut c_void) {
+        match PREVIOUS.get() {
+            Some(previous) if previous.sa_sigaction != libc::SIG_DFL && previous.sa_sigaction != libc::SIG_IGN => {
+                if previous.sa_flags & libc::SA_SIGINFO != 0 {
+                    let handler = std::mem::transmute::<usize, extern "C" fn(c_int, *mut siginfo_t, *mut c_void)>(previous.sa_sigaction);
+                    handler(signal, info, context);
+                } else {
+                    let handler = std::mem::transmute::<usize, extern "C" fn(c_int)>(previous.sa_sigaction);
+                    handler(signal);
+                }
+            }
+            _ => {
+                // the faulting instruction runs again and the default action ends the process
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = libc::SIG_DFL;
+                libc::sigaction(signal, &action, std::ptr::null_mut());
+            }
+        }
+    }
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "x86_64")))]
+mod sys {
+    use crate::vm::{ConfigError, IO};
+
+    /// Guard pages are not implemented on this platform.
+    pub(crate) enum GuardedTape {}
+
+    impl GuardedTape {
+        pub(crate) fn new(_tape: usize, _guard: usize) -> anyhow::Result<Self> {
+            Err(ConfigError::GuardPagesUnsupported.into())
+        }
+
+        pub(crate) unsafe fn call(&mut self, _func: *const u8, _io: *mut IO) -> i64 {
+            match *self {}
+        }
+    }
+}
@@ -1,14 +1,17 @@
-use std::{io::{Read, Write}, time::Duration};
+use std::{cell::Cell, io::{Read, Write}, time::Duration};
 use thiserror::Error;
 
+use inkwell::basic_block::BasicBlock;
 use inkwell::builder::Builder;
 use inkwell::context::Context;
 use inkwell::execution_engine::{ExecutionEngine, JitFunction};
 use inkwell::module::Module;
-use inkwell::{AddressSpace, OptimizationLevel};
-use inkwell::values::PointerValue;
-use crate::ir::BrainfuckIR;
-use crate::vm::{VMInterface, IO, MEMORY_SIZE, bf_put, bf_get};
+use inkwell::{AddressSpace, AtomicOrdering, OptimizationLevel};
+use inkwell::types::IntType;
+use inkwell::values::{BasicValue, IntValue, PointerValue};
+use crate::ir::{walk, BrainfuckIR, BrainfuckNode, Walk};
+use crate::vm::{BoundsChecks, CellWidth, EofPolicy, TapePolicy, VMConfig, VMInterface, IO, BF_GET_ERROR, JIT_EXIT_OVERFLOW, JIT_EXIT_IO_ERROR, JIT_EXIT_FUEL_EXHAUSTED, JIT_EXIT_CANCELLED, JIT_EXIT_NEED_INPUT, JIT_EXIT_OUTPUT_FULL, BF_GET_BLOCKED, CancelHandle, Stop, bf_put, bf_get, bf_scan, bf_write, bf_suspend, bf_resume, count_resume_points, exit_status, resume_status};
+use crate::vm::guard::JitTape;
 
 type JITFunc = unsafe extern "C" fn(*mut u8, *mut IO) -> u64;
 
@@ -28,12 +31,33 @@ enum LLVMError {
     GetNoneFunction,
     #[error("LLVM error: function {0} not imported")]
     FunctionNotImported(String),
+    #[error("LLVM error: call to {0} returned no value")]
+    NoReturnValue(String),
     #[error("LLVM io error: {0}")]
     IOError(String),
     #[error("Invalid IR Found: {0}")]
     InvalidIR(String),
 }
 
+/// Values shared by every instruction of `bf_jit_main`.
+struct Frame<'ctx> {
+    // start of the tape
+    memory: PointerValue<'ctx>,
+    // alloca holding the pointer to the current cell
+    ptr: PointerValue<'ctx>,
+    // alloca holding the IO context
+    io: PointerValue<'ctx>,
+    // alloca holding the loop iterations left, if they are limited
+    fuel: Option<PointerValue<'ctx>>,
+    // the flag of the cancel handle
+    cancelled: PointerValue<'ctx>,
+    // alloca bf_resume stores the pointer and fuel of a resumed run in
+    saved: PointerValue<'ctx>,
+    // where each `,` or `.` is resumed, in the order they are compiled
+    resume_blocks: Vec<BasicBlock<'ctx>>,
+    next_point: Cell<usize>,
+}
+
 struct JITContext<'ctx> {
     context: &'ctx Context,
     module: Module<'ctx>,
@@ -41,10 +65,21 @@ struct JITContext<'ctx> {
     execution_engine: ExecutionEngine<'ctx>,
     jit_func: Option<JitFunction<'ctx, JITFunc>>,
     ir: String,
+    cell_width: CellWidth,
+    tape_len: usize,
+    // the pointer wraps around at the ends of the tape
+    wrap: bool,
+    // index of the cell the pointer starts at
+    origin: usize,
+    // pointers are checked against the ends of the tape
+    checked: bool,
+    eof: EofPolicy,
+    fuel: Option<u64>,
+    cancel: CancelHandle,
 }
 
 impl<'ctx> JITContext<'ctx> {
-    fn new(context: &'ctx Context) -> anyhow::Result<Self> {
+    fn new(context: &'ctx Context, config: &VMConfig, cancel: CancelHandle) -> anyhow::Result<Self> {
         let module = context.create_module("bf-jit-module");
         let execution_engine = module
             .create_jit_execution_engine(OptimizationLevel::Aggressive)
@@ -57,10 +92,19 @@ impl<'ctx> JITContext<'ctx> {
             execution_engine,
             jit_func: None,
             ir: String::new(),
+            cell_width: config.cell_width,
+            tape_len: config.tape_len(),
+            wrap: config.tape_policy == TapePolicy::Wrap,
+            origin: config.origin(),
+            // a wrapping pointer never leaves the tape
+            checked: config.bounds_checks == BoundsChecks::Explicit && config.tape_policy == TapePolicy::Fixed,
+            eof: config.eof,
+            fuel: config.fuel,
+            cancel,
         })
     }
 
-    fn compile(&mut self, ir: &[BrainfuckIR]) -> anyhow::Result<()> {
+    fn compile(&mut self, ir: &[BrainfuckNode]) -> anyhow::Result<()> {
         let i64_type = self.context.i64_type();
         let i8_type = self.context.i8_type();
         let ptr_type = self.context.ptr_type(AddressSpace::default());
@@ -71,12 +115,22 @@ impl<'ctx> JITContext<'ctx> {
         let basic_block = self.context.append_basic_block(function, "entry");
         self.builder.position_at_end(basic_block);
 
-        let put_fn_type = self.context
-            .void_type()
-            .fn_type(&[ptr_type.into(), i8_type.into()], false);
+        let put_fn_type = i64_type.fn_type(&[ptr_type.into(), i8_type.into()], false);
         let bf_put_val = self.module.add_function("bf_put", put_fn_type, None);
-        let get_fn_type = i8_type.fn_type(&[ptr_type.into()], false);
+        let get_fn_type = self.context.i32_type().fn_type(&[ptr_type.into()], false);
         let bf_get_val = self.module.add_function("bf_get", get_fn_type, None);
+        let scan_fn_type = i64_type.fn_type(
+            &[ptr_type.into(), i64_type.into(), i64_type.into(), i64_type.into(), i64_type.into(), i8_type.into()],
+            false,
+        );
+        let bf_scan_val = self.module.add_function("bf_scan", scan_fn_type, None);
+        let write_fn_type = i64_type.fn_type(&[ptr_type.into(), ptr_type.into(), i64_type.into()], false);
+        let bf_write_val = self.module.add_function("bf_write", write_fn_type, None);
+        let suspend_fn_type = self.context.void_type()
+            .fn_type(&[ptr_type.into(), i64_type.into(), i64_type.into(), i64_type.into()], false);
+        let bf_suspend_val = self.module.add_function("bf_suspend", suspend_fn_type, None);
+        let resume_fn_type = i64_type.fn_type(&[ptr_type.into(), ptr_type.into()], false);
+        let bf_resume_val = self.module.add_function("bf_resume", resume_fn_type, None);
 
         self.execution_engine.add_global_mapping(
             &bf_put_val,
@@ -86,6 +140,22 @@ impl<'ctx> JITContext<'ctx> {
             &bf_get_val,
             bf_get as usize,
         );
+        self.execution_engine.add_global_mapping(
+            &bf_scan_val,
+            bf_scan as usize,
+        );
+        self.execution_engine.add_global_mapping(
+            &bf_write_val,
+            bf_write as usize,
+        );
+        self.execution_engine.add_global_mapping(
+            &bf_suspend_val,
+            bf_suspend as usize,
+        );
+        self.execution_engine.add_global_mapping(
+            &bf_resume_val,
+            bf_resume as usize,
+        );
 
         let memory_ptr = function
             .get_nth_param(0)
@@ -98,13 +168,74 @@ impl<'ctx> JITContext<'ctx> {
 
         let memory = self.builder
             .build_alloca(ptr_type, "mem_ptr")?;
-        self.builder.build_store(memory, memory_ptr)?;
+        let start_ptr = unsafe {
+            self.builder.build_gep(self.cell_type(), memory_ptr, &[i64_type
+                .const_int(self.origin as u64, false)], "start_ptr")?
+        };
+        self.builder.build_store(memory, start_ptr)?;
         let io = self.builder
             .build_alloca(ptr_type, "io_ptr")?;
         self.builder.build_store(io, io_ptr)?;
+        let fuel = match self.fuel {
+            Some(limit) => {
+                let fuel = self.builder.build_alloca(i64_type, "fuel")?;
+                self.builder.build_store(fuel, i64_type.const_int(limit, false))?;
+                Some(fuel)
+            }
+            None => None,
+        };
+        // the handle outlives the compiled code
+        let cancelled = i64_type
+            .const_int(self.cancel.flag() as u64, false)
+            .const_to_pointer(ptr_type);
+
+        // a run suspended at the n-th `,` or `.` continues at the n-th resume block, which
+        // restores the pointer and fuel bf_resume put in `saved`
+        let saved = self.builder.build_alloca(i64_type.array_type(2), "saved")?;
+        let resume_blocks: Vec<_> = (0..count_resume_points(ir))
+            .map(|_| self.context.append_basic_block(function, "resume"))
+            .collect();
+        if !resume_blocks.is_empty() {
+            let point = self.builder
+                .build_call(bf_resume_val, &[io_ptr.into(), saved.into()], "point")?
+                .try_as_basic_value()
+                .left()
+                .ok_or_else(|| LLVMError::NoReturnValue("bf_resume".to_string()))?
+                .into_int_value();
+            let start = self.context.append_basic_block(function, "start");
+            let cases: Vec<_> = resume_blocks.iter()
+                .enumerate()
+                .map(|(n, &block)| (i64_type.const_int(n as u64 + 1, false), block))
+                .collect();
+            self.builder.build_switch(point, start, &cases)?;
+            self.builder.position_at_end(start);
+        }
 
-        for inst in ir {
-            self.compile_instruction(inst, &memory, &io)?;
+        let frame = Frame {
+            memory: memory_ptr,
+            ptr: memory,
+            io,
+            fuel,
+            cancelled,
+            saved,
+            resume_blocks,
+            next_point: Cell::new(0),
+        };
+        // the check and end block of every loop being compiled, innermost last
+        let mut loops = Vec::new();
+        for step in walk(ir) {
+            match step {
+                Walk::Enter(node) => self.compile_instruction(node, &frame, &mut loops)?,
+                Walk::Exit(_) => {
+                    let (loop_check, loop_end) = loops.pop().expect("every loop is entered before it ends");
+                    // pay for the iteration and stop if cancelled
+                    self.spend_fuel(&frame)?;
+                    self.poll_cancel(&frame)?;
+                    self.builder.build_unconditional_branch(loop_check)?;
+
+                    self.builder.position_at_end(loop_end);
+                }
+            }
         }
 
         let zero = i64_type.const_zero();
@@ -122,63 +253,459 @@ impl<'ctx> JITContext<'ctx> {
         Ok(())
     }
 
-    fn compile_instruction(&self, ir: &BrainfuckIR, ptr: &PointerValue, io: &PointerValue) -> anyhow::Result<()> {
+    fn cell_type(&self) -> IntType<'ctx> {
+        self.context.custom_width_int_type(self.cell_width.bits())
+    }
+
+    /// An IR constant truncated to the cell width.
+    fn cell_const(&self, n: u32) -> IntValue<'ctx> {
+        self.cell_type().const_int(self.cell_width.truncate(n) as u64, false)
+    }
+
+    /// Pointer to the cell `offset` away from the current one.
+    fn cell_ptr(&self, frame: &Frame<'ctx>, offset: i32) -> anyhow::Result<PointerValue<'ctx>> {
         let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let current_ptr = self.builder
+            .build_load(ptr_type, frame.ptr, "mem_ptr")?
+            .into_pointer_value();
+        if offset == 0 {
+            return Ok(current_ptr);
+        }
+        if self.wrap {
+            // the cell may be across the end of the tape
+            return self.wrapped_ptr(frame, offset as i64);
+        }
+        if self.checked {
+            self.check_bounds(frame, offset as i64)?;
+        }
+        let cell_ptr = unsafe {
+            self.builder.build_gep(self.cell_type(), current_ptr, &[self.context
+                .i64_type()
+                .const_int(offset as i64 as u64, true)], "cell_ptr")?
+        };
+        Ok(cell_ptr)
+    }
+
+    /// Return `JIT_EXIT_OVERFLOW` from `bf_jit_main` unless the cell `delta` away from the
+    /// current one is on the tape.
+    fn check_bounds(&self, frame: &Frame<'ctx>, delta: i64) -> anyhow::Result<()> {
+        let i64_type = self.context.i64_type();
+        let index = self.cell_index(frame)?;
+        // a negative index compares as a huge unsigned one
+        let target = self.builder
+            .build_int_add(index, i64_type.const_int(delta as u64, true), "target_index")?;
+        let off_tape = self.builder
+            .build_int_compare(
+                inkwell::IntPredicate::UGE,
+                target,
+                i64_type.const_int(self.tape_len as u64, false),
+                "off_tape",
+            )?;
+
+        let function = self.builder
+            .get_insert_block()
+            .ok_or_else(|| LLVMError::GetNoneBlock)?
+            .get_parent()
+            .ok_or_else(|| LLVMError::GetNoneFunction)?;
+        let overflow = self.context.append_basic_block(function, "overflow");
+        let on_tape = self.context.append_basic_block(function, "on_tape");
+        self.builder.build_conditional_branch(off_tape, overflow, on_tape)?;
+
+        self.builder.position_at_end(overflow);
+        self.builder.build_return(Some(&i64_type.const_int(JIT_EXIT_OVERFLOW as u64, false)))?;
+
+        self.builder.position_at_end(on_tape);
+        Ok(())
+    }
+
+    /// Take a unit of fuel, return `JIT_EXIT_FUEL_EXHAUSTED` from `bf_jit_main` if there is
+    /// none left.
+    fn spend_fuel(&self, frame: &Frame<'ctx>) -> anyhow::Result<()> {
+        let Some(fuel_ptr) = frame.fuel else {
+            return Ok(());
+        };
+        let i64_type = self.context.i64_type();
+        let fuel = self.builder
+            .build_load(i64_type, fuel_ptr, "fuel")?
+            .into_int_value();
+        let empty = self.builder
+            .build_int_compare(inkwell::IntPredicate::EQ, fuel, i64_type.const_zero(), "empty")?;
+
+        let function = self.builder
+            .get_insert_block()
+            .ok_or_else(|| LLVMError::GetNoneBlock)?
+            .get_parent()
+            .ok_or_else(|| LLVMError::GetNoneFunction)?;
+        let out_of_fuel = self.context.append_basic_block(function, "out_of_fuel");
+        let fueled = self.context.append_basic_block(function, "fueled");
+        self.builder.build_conditional_branch(empty, out_of_fuel, fueled)?;
+
+        self.builder.position_at_end(out_of_fuel);
+        self.builder.build_return(Some(&i64_type.const_int(JIT_EXIT_FUEL_EXHAUSTED as u64, false)))?;
+
+        self.builder.position_at_end(fueled);
+        let rest = self.builder
+            .build_int_sub(fuel, i64_type.const_int(1, false), "rest")?;
+        self.builder.build_store(fuel_ptr, rest)?;
+        Ok(())
+    }
+
+    /// Return `JIT_EXIT_CANCELLED` from `bf_jit_main` if the run has been cancelled.
+    fn poll_cancel(&self, frame: &Frame<'ctx>) -> anyhow::Result<()> {
         let i8_type = self.context.i8_type();
-        match ir {
-            BrainfuckIR::AddVal(n) => {
-                let current_ptr = self.builder
-                    .build_load(ptr_type, *ptr, "mem_ptr")?
-                    .into_pointer_value();
+        let flag = self.builder.build_load(i8_type, frame.cancelled, "cancelled")?;
+        // atomic so the load is redone on every iteration
+        flag.as_instruction_value()
+            .ok_or_else(|| LLVMError::InvalidIR("load is not an instruction".to_string()))?
+            .set_atomic_ordering(AtomicOrdering::Monotonic)
+            .map_err(|err| LLVMError::InvalidIR(err.to_string()))?;
+        let cancelled = self.builder
+            .build_int_compare(inkwell::IntPredicate::NE, flag.into_int_value(), i8_type.const_zero(), "is_cancelled")?;
+
+        let function = self.builder
+            .get_insert_block()
+            .ok_or_else(|| LLVMError::GetNoneBlock)?
+            .get_parent()
+            .ok_or_else(|| LLVMError::GetNoneFunction)?;
+        let stop = self.context.append_basic_block(function, "cancelled");
+        let running = self.context.append_basic_block(function, "running");
+        self.builder.build_conditional_branch(cancelled, stop, running)?;
+
+        self.builder.position_at_end(stop);
+        let i64_type = self.context.i64_type();
+        self.builder.build_return(Some(&i64_type.const_int(JIT_EXIT_CANCELLED as u64, false)))?;
+
+        self.builder.position_at_end(running);
+        Ok(())
+    }
+
+    /// Start the block the next `,`, or the code after the next `.`, runs in, a run suspended
+    /// there is resumed at its start. Returns the number `bf_suspend` saves for it.
+    fn resume_point(&self, frame: &Frame<'ctx>) -> anyhow::Result<u64> {
+        let n = frame.next_point.get();
+        frame.next_point.set(n + 1);
+        let function = self.builder
+            .get_insert_block()
+            .ok_or_else(|| LLVMError::GetNoneBlock)?
+            .get_parent()
+            .ok_or_else(|| LLVMError::GetNoneFunction)?;
+        let read = self.context.append_basic_block(function, "read");
+        self.builder.build_unconditional_branch(read)?;
+
+        // restore the pointer and fuel, the code continues as if it hadn't stopped
+        let i64_type = self.context.i64_type();
+        self.builder.position_at_end(frame.resume_blocks[n]);
+        let pointer = self.builder
+            .build_load(i64_type, frame.saved, "saved_ptr")?
+            .into_int_value();
+        let pointer = self.builder
+            .build_int_to_ptr(pointer, self.context.ptr_type(AddressSpace::default()), "mem_ptr")?;
+        self.builder.build_store(frame.ptr, pointer)?;
+        if let Some(fuel_ptr) = frame.fuel {
+            let saved_fuel = unsafe {
+                self.builder.build_gep(i64_type, frame.saved, &[i64_type.const_int(1, false)], "saved_fuel")?
+            };
+            let fuel = self.builder.build_load(i64_type, saved_fuel, "fuel")?;
+            self.builder.build_store(fuel_ptr, fuel)?;
+        }
+        self.builder.build_unconditional_branch(read)?;
+
+        self.builder.position_at_end(read);
+        Ok(n as u64 + 1)
+    }
+
+    /// Save the pointer and fuel for resuming at `point` and return `code` from `bf_jit_main`,
+    /// `JIT_EXIT_NEED_INPUT` or `JIT_EXIT_OUTPUT_FULL`.
+    fn suspend(&self, frame: &Frame<'ctx>, point: u64, code: i64) -> anyhow::Result<()> {
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let io = self.builder
+            .build_load(ptr_type, frame.io, "io_ptr")?
+            .into_pointer_value();
+        let pointer = self.builder
+            .build_load(ptr_type, frame.ptr, "mem_ptr")?
+            .into_pointer_value();
+        let pointer = self.builder.build_ptr_to_int(pointer, i64_type, "saved_ptr")?;
+        let fuel = match frame.fuel {
+            Some(fuel_ptr) => self.builder.build_load(i64_type, fuel_ptr, "fuel")?.into_int_value(),
+            None => i64_type.const_zero(),
+        };
+        let suspend_fn = self.module
+            .get_function("bf_suspend")
+            .ok_or_else(|| LLVMError::FunctionNotImported("bf_suspend".to_string()))?;
+        self.builder.build_call(
+            suspend_fn,
+            &[io.into(), i64_type.const_int(point, false).into(), pointer.into(), fuel.into()],
+            "call_suspend",
+        )?;
+        self.builder.build_return(Some(&i64_type.const_int(code as u64, false)))?;
+        Ok(())
+    }
+
+    /// Continue after `bf_put` or `bf_write` returned `code`. A write that filled the output
+    /// suspends the run, it is resumed right after the write.
+    fn after_write(&self, frame: &Frame<'ctx>, code: IntValue<'ctx>) -> anyhow::Result<()> {
+        let full = self.builder.build_int_compare(
+            inkwell::IntPredicate::EQ,
+            code,
+            code.get_type().const_int(JIT_EXIT_OUTPUT_FULL as u64, false),
+            "full",
+        )?;
+        let function = self.builder
+            .get_insert_block()
+            .ok_or_else(|| LLVMError::GetNoneBlock)?
+            .get_parent()
+            .ok_or_else(|| LLVMError::GetNoneFunction)?;
+        let full_block = self.context.append_basic_block(function, "output_full");
+        let check = self.context.append_basic_block(function, "check");
+        self.builder.build_conditional_branch(full, full_block, check)?;
+        self.builder.position_at_end(check);
+        self.exit_unless_ok(code)?;
+        let point = self.resume_point(frame)?;
+        let next = self.builder.get_insert_block().ok_or_else(|| LLVMError::GetNoneBlock)?;
+
+        self.builder.position_at_end(full_block);
+        self.suspend(frame, point, JIT_EXIT_OUTPUT_FULL)?;
+        self.builder.position_at_end(next);
+        Ok(())
+    }
+
+    /// Return `code` from `bf_jit_main` unless it is `JIT_EXIT_OK`.
+    fn exit_unless_ok(&self, code: IntValue<'ctx>) -> anyhow::Result<()> {
+        let failed = self.builder
+            .build_int_compare(inkwell::IntPredicate::NE, code, code.get_type().const_zero(), "failed")?;
+
+        let function = self.builder
+            .get_insert_block()
+            .ok_or_else(|| LLVMError::GetNoneBlock)?
+            .get_parent()
+            .ok_or_else(|| LLVMError::GetNoneFunction)?;
+        let io_failed = self.context.append_basic_block(function, "io_failed");
+        let io_ok = self.context.append_basic_block(function, "io_ok");
+        self.builder.build_conditional_branch(failed, io_failed, io_ok)?;
+
+        self.builder.position_at_end(io_failed);
+        self.builder.build_return(Some(&code))?;
+
+        self.builder.position_at_end(io_ok);
+        Ok(())
+    }
+
+    /// Index of the current cell.
+    fn cell_index(&self, frame: &Frame<'ctx>) -> anyhow::Result<IntValue<'ctx>> {
+        let i64_type = self.context.i64_type();
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let current_ptr = self.builder
+            .build_load(ptr_type, frame.ptr, "mem_ptr")?
+            .into_pointer_value();
+        let current_addr = self.builder
+            .build_ptr_to_int(current_ptr, i64_type, "current_addr")?;
+        let memory_addr = self.builder
+            .build_ptr_to_int(frame.memory, i64_type, "memory_addr")?;
+        let distance = self.builder
+            .build_int_sub(current_addr, memory_addr, "distance")?;
+        let index = self.builder
+            .build_int_unsigned_div(
+                distance,
+                i64_type.const_int(self.cell_width.bytes() as u64, false),
+                "index",
+            )?;
+        Ok(index)
+    }
+
+    /// Pointer to the cell `delta` away from the current one on a tape whose ends are joined.
+    fn wrapped_ptr(&self, frame: &Frame<'ctx>, delta: i64) -> anyhow::Result<PointerValue<'ctx>> {
+        let i64_type = self.context.i64_type();
+        let len = i64_type.const_int(self.tape_len as u64, false);
+        let index = self.cell_index(frame)?;
+        let sum = self.builder
+            .build_int_add(index, i64_type.const_int(delta.rem_euclid(self.tape_len as i64) as u64, false), "sum")?;
+        let wrapped = self.builder
+            .build_int_sub(sum, len, "wrapped")?;
+        let past_end = self.builder
+            .build_int_compare(inkwell::IntPredicate::UGE, sum, len, "past_end")?;
+        let new_index = self.builder
+            .build_select(past_end, wrapped, sum, "new_index")?
+            .into_int_value();
+        let new_ptr = unsafe {
+            self.builder.build_gep(self.cell_type(), frame.memory, &[new_index], "new_ptr")?
+        };
+        Ok(new_ptr)
+    }
+
+    /// Compile `node`. A loop is compiled up to the start of its body and pushed onto `loops`,
+    /// the caller closes it once the body is compiled. The span of the node isn't kept, the
+    /// code has no debug locations and the errors it stops with no source position.
+    fn compile_instruction(
+        &self,
+        node: &BrainfuckNode,
+        frame: &Frame<'ctx>,
+        loops: &mut Vec<(BasicBlock<'ctx>, BasicBlock<'ctx>)>,
+    ) -> anyhow::Result<()> {
+        let ptr_type = self.context.ptr_type(AddressSpace::default());
+        let i8_type = self.context.i8_type();
+        let cell_type = self.cell_type();
+        let ptr = &frame.ptr;
+        let io = &frame.io;
+        match &node.ir {
+            BrainfuckIR::AddVal(n, offset) => {
+                let current_ptr = self.cell_ptr(frame, *offset)?;
                 let current_val = self.builder
-                    .build_load(i8_type, current_ptr, "mem_val")?
+                    .build_load(cell_type, current_ptr, "mem_val")?
                     .into_int_value();
                 let new_val = self.builder
-                    .build_int_add(current_val, self.context.
-                        i8_type().const_int(*n as u64, false), "new_val")?;
+                    .build_int_add(current_val, self.cell_const(*n), "new_val")?;
                 self.builder.build_store(current_ptr, new_val)?;
             }
-            BrainfuckIR::SubVal(n) => {
-                let current_ptr = self.builder
-                    .build_load(ptr_type, *ptr, "mem_ptr")?
-                    .into_pointer_value();
+            BrainfuckIR::SubVal(n, offset) => {
+                let current_ptr = self.cell_ptr(frame, *offset)?;
                 let current_val = self.builder
-                    .build_load(i8_type, current_ptr, "mem_val")?
+                    .build_load(cell_type, current_ptr, "mem_val")?
                     .into_int_value();
                 let new_val = self.builder
-                    .build_int_sub(current_val, self.context.
-                        i8_type().const_int(*n as u64, false), "new_val")?;
+                    .build_int_sub(current_val, self.cell_const(*n), "new_val")?;
                 self.builder.build_store(current_ptr, new_val)?;
             }
+            BrainfuckIR::SetVal(n, offset) => {
+                let current_ptr = self.cell_ptr(frame, *offset)?;
+                self.builder.build_store(current_ptr, self.cell_const(*n))?;
+            }
+            BrainfuckIR::MulAdd { src, offset, factor } => {
+                let current_ptr = self.cell_ptr(frame, *src)?;
+                let current_val = self.builder
+                    .build_load(cell_type, current_ptr, "mem_val")?
+                    .into_int_value();
+
+                // the loop this came from never ran on a zero counter, so the target may
+                // be off the tape and is only touched once the counter isn't zero
+                let mul_end = if !self.wrap {
+                    let function = self.builder
+                        .get_insert_block()
+                        .ok_or_else(|| LLVMError::GetNoneBlock)?
+                        .get_parent()
+                        .ok_or_else(|| LLVMError::GetNoneFunction)?;
+                    let mul_add = self.context.append_basic_block(function, "mul_add");
+                    let mul_end = self.context.append_basic_block(function, "mul_end");
+                    let is_zero = self.builder
+                        .build_int_compare(inkwell::IntPredicate::EQ, current_val, cell_type.const_zero(), "is_zero")?;
+                    self.builder.build_conditional_branch(is_zero, mul_end, mul_add)?;
+                    self.builder.position_at_end(mul_add);
+                    Some(mul_end)
+                } else {
+                    None
+                };
+
+                let target_ptr = self.cell_ptr(frame, *offset)?;
+                let target_val = self.builder
+                    .build_load(cell_type, target_ptr, "target_val")?
+                    .into_int_value();
+                let product = self.builder
+                    .build_int_mul(current_val, self.cell_const(*factor), "product")?;
+                let new_val = self.builder
+                    .build_int_add(target_val, product, "new_val")?;
+                self.builder.build_store(target_ptr, new_val)?;
+
+                if let Some(mul_end) = mul_end {
+                    self.builder.build_unconditional_branch(mul_end)?;
+                    self.builder.position_at_end(mul_end);
+                }
+            }
+            BrainfuckIR::PtrMovRight(n) if self.wrap => {
+                let new_ptr = self.wrapped_ptr(frame, *n as i64)?;
+                self.builder.build_store(*ptr, new_ptr)?;
+            }
+            BrainfuckIR::PtrMovLeft(n) if self.wrap => {
+                let new_ptr = self.wrapped_ptr(frame, -(*n as i64))?;
+                self.builder.build_store(*ptr, new_ptr)?;
+            }
             BrainfuckIR::PtrMovRight(n) => {
+                if self.checked {
+                    self.check_bounds(frame, *n as i64)?;
+                }
                 let current_ptr = self.builder
                     .build_load(ptr_type, *ptr, "mem_ptr")?
                     .into_pointer_value();
                 let new_ptr = unsafe {
-                    self.builder.build_gep(ptr_type, current_ptr, &[self.context
+                    self.builder.build_gep(cell_type, current_ptr, &[self.context
                         .i64_type().const_int(*n as u64, false)], "new_ptr")?
                 };
                 self.builder.build_store(*ptr, new_ptr)?;
             }
             BrainfuckIR::PtrMovLeft(n) => {
+                if self.checked {
+                    self.check_bounds(frame, -(*n as i64))?;
+                }
                 let current_ptr = self.builder
                     .build_load(ptr_type, *ptr, "mem_ptr")?
                     .into_pointer_value();
                 let offset = -(*n as i64);
                 let new_ptr = unsafe {
-                    self.builder.build_gep(ptr_type, current_ptr, &[self.context
+                    self.builder.build_gep(cell_type, current_ptr, &[self.context
                         .i64_type()
                         .const_int(offset as u64, true)], "new_ptr")?
                 };
                 self.builder.build_store(*ptr, new_ptr)?;
             }
-            BrainfuckIR::PutByte => {
-                let current_ptr = self.builder
-                    .build_load(ptr_type, *ptr, "mem_ptr")?
-                    .into_pointer_value();
+            BrainfuckIR::ScanRight(n) | BrainfuckIR::ScanLeft(n) => {
+                let i64_type = self.context.i64_type();
+                let stride = match &node.ir {
+                    BrainfuckIR::ScanRight(_) => *n as i64,
+                    _ => -(*n as i64),
+                };
+
+                let index = self.cell_index(frame)?;
+
+                let scan_fn = self.module
+                    .get_function("bf_scan")
+                    .ok_or_else(|| LLVMError::FunctionNotImported("bf_scan".to_string()))?;
+                let found = self.builder
+                    .build_call(
+                        scan_fn,
+                        &[
+                            frame.memory.into(),
+                            i64_type.const_int(self.tape_len as u64, false).into(),
+                            index.into(),
+                            i64_type.const_int(stride as u64, true).into(),
+                            i64_type.const_int(self.cell_width.bytes() as u64, false).into(),
+                            i8_type.const_int(self.wrap as u64, false).into(),
+                        ],
+                        "call_scan"
+                    )?
+                    .try_as_basic_value()
+                    .left()
+                    .ok_or_else(|| LLVMError::NoReturnValue("bf_scan".to_string()))?
+                    .into_int_value();
+
+                // a negative result is the negated exit code
+                let function = self.builder
+                    .get_insert_block()
+                    .ok_or_else(|| LLVMError::GetNoneBlock)?
+                    .get_parent()
+                    .ok_or_else(|| LLVMError::GetNoneFunction)?;
+                let scan_failed = self.context.append_basic_block(function, "scan_failed");
+                let scan_found = self.context.append_basic_block(function, "scan_found");
+                let failed = self.builder
+                    .build_int_compare(inkwell::IntPredicate::SLT, found, i64_type.const_zero(), "failed")?;
+                self.builder.build_conditional_branch(failed, scan_failed, scan_found)?;
+
+                self.builder.position_at_end(scan_failed);
+                let code = self.builder.build_int_neg(found, "code")?;
+                self.builder.build_return(Some(&code))?;
+
+                self.builder.position_at_end(scan_found);
+                let new_ptr = unsafe {
+                    self.builder.build_gep(cell_type, frame.memory, &[found], "new_ptr")?
+                };
+                self.builder.build_store(*ptr, new_ptr)?;
+            }
+            BrainfuckIR::PutByte(offset) => {
+                let current_ptr = self.cell_ptr(frame, *offset)?;
                 let current_val = self.builder
-                    .build_load(i8_type, current_ptr, "mem_val")?
+                    .build_load(cell_type, current_ptr, "mem_val")?
                     .into_int_value();
+                // only the low byte of a wide cell is written
+                let current_byte = self.builder
+                    .build_int_truncate_or_bit_cast(current_val, i8_type, "mem_byte")?;
 
                 let io = self.builder
                     .build_load(ptr_type, *io, "io_ptr")?
@@ -187,18 +714,52 @@ impl<'ctx> JITContext<'ctx> {
                 let put_fn = self.module
                     .get_function("bf_put")
                     .ok_or_else(|| LLVMError::FunctionNotImported("bf_put".to_string()))?;
-                self.builder
+                let code = self.builder
                     .build_call(
                         put_fn,
-                        &[io.into(), current_val.into()],
+                        &[io.into(), current_byte.into()],
                         "call_put"
-                    )?;
+                    )?
+                    .try_as_basic_value()
+                    .left()
+                    .ok_or_else(|| LLVMError::NoReturnValue("bf_put".to_string()))?
+                    .into_int_value();
+                self.after_write(frame, code)?;
             }
-            BrainfuckIR::GetByte => {
-                let current_ptr = self.builder
-                    .build_load(ptr_type, *ptr, "mem_ptr")?
+            BrainfuckIR::PutBytes(bytes) => {
+                // the bytes live in a constant global
+                let data = self.context.const_string(bytes, false);
+                let global = self.module.add_global(data.get_type(), None, "output");
+                global.set_initializer(&data);
+                global.set_constant(true);
+
+                let io = self.builder
+                    .build_load(ptr_type, *io, "io_ptr")?
                     .into_pointer_value();
 
+                let write_fn = self.module
+                    .get_function("bf_write")
+                    .ok_or_else(|| LLVMError::FunctionNotImported("bf_write".to_string()))?;
+                let code = self.builder
+                    .build_call(
+                        write_fn,
+                        &[
+                            io.into(),
+                            global.as_pointer_value().into(),
+                            self.context.i64_type().const_int(bytes.len() as u64, false).into(),
+                        ],
+                        "call_write"
+                    )?
+                    .try_as_basic_value()
+                    .left()
+                    .ok_or_else(|| LLVMError::NoReturnValue("bf_write".to_string()))?
+                    .into_int_value();
+                self.after_write(frame, code)?;
+            }
+            BrainfuckIR::GetByte(offset) => {
+                let point = self.resume_point(frame)?;
+                let current_ptr = self.cell_ptr(frame, *offset)?;
+
                 let io = self.builder
                     .build_load(ptr_type, *io, "io_ptr")?
                     .into_pointer_value();
@@ -217,9 +778,67 @@ impl<'ctx> JITContext<'ctx> {
                     .ok_or_else(|| LLVMError::IOError(String::from("Could not read byte")))?
                     .into_int_value();
 
-                self.builder.build_store(current_ptr, byte_read)?;
+                // BF_GET_ERROR stops the program with the error bf_get kept
+                let read_failed = self.builder
+                    .build_int_compare(
+                        inkwell::IntPredicate::EQ,
+                        byte_read,
+                        byte_read.get_type().const_int(BF_GET_ERROR as u64, true),
+                        "read_failed",
+                    )?;
+                let code = self.builder
+                    .build_select(
+                        read_failed,
+                        self.context.i64_type().const_int(JIT_EXIT_IO_ERROR as u64, false),
+                        self.context.i64_type().const_zero(),
+                        "code",
+                    )?
+                    .into_int_value();
+                self.exit_unless_ok(code)?;
+
+                // BF_GET_BLOCKED suspends the program until it is resumed with more input
+                let blocked = self.builder
+                    .build_int_compare(
+                        inkwell::IntPredicate::EQ,
+                        byte_read,
+                        byte_read.get_type().const_int(BF_GET_BLOCKED as u64, true),
+                        "blocked",
+                    )?;
+                let function = self.builder
+                    .get_insert_block()
+                    .ok_or_else(|| LLVMError::GetNoneBlock)?
+                    .get_parent()
+                    .ok_or_else(|| LLVMError::GetNoneFunction)?;
+                let suspend = self.context.append_basic_block(function, "suspend");
+                let got = self.context.append_basic_block(function, "got");
+                self.builder.build_conditional_branch(blocked, suspend, got)?;
+                self.builder.position_at_end(suspend);
+                self.suspend(frame, point, JIT_EXIT_NEED_INPUT)?;
+                self.builder.position_at_end(got);
+
+                // bf_get returns -1 at the end of input, truncated to the cell width the byte
+                // is zero-extended and -1 has every bit set
+                let is_eof = self.builder
+                    .build_int_compare(inkwell::IntPredicate::SLT, byte_read, byte_read.get_type().const_zero(), "is_eof")?;
+                let read_val = self.builder
+                    .build_int_truncate_or_bit_cast(byte_read, cell_type, "read_val")?;
+                let new_val = match self.eof {
+                    EofPolicy::Zero => self.builder
+                        .build_select(is_eof, cell_type.const_zero(), read_val, "new_val")?
+                        .into_int_value(),
+                    EofPolicy::MinusOne => read_val,
+                    EofPolicy::Unchanged => {
+                        let current_val = self.builder
+                            .build_load(cell_type, current_ptr, "mem_val")?
+                            .into_int_value();
+                        self.builder
+                            .build_select(is_eof, current_val, read_val, "new_val")?
+                            .into_int_value()
+                    }
+                };
+                self.builder.build_store(current_ptr, new_val)?;
             }
-            BrainfuckIR::Loop(body) => {
+            BrainfuckIR::Loop(_) => {
                 let function = self.builder
                     .get_insert_block()
                     .ok_or_else(|| LLVMError::GetNoneBlock)?
@@ -236,24 +855,19 @@ impl<'ctx> JITContext<'ctx> {
                     .build_load(ptr_type, *ptr, "mem_ptr")?
                     .into_pointer_value();
                 let current_val = self.builder
-                    .build_load(i8_type, current_ptr, "mem_val")?
+                    .build_load(cell_type, current_ptr, "mem_val")?
                     .into_int_value();
                 let is_zero = self.builder
                     .build_int_compare(
                         inkwell::IntPredicate::EQ,
                         current_val,
-                        self.context.i8_type().const_zero(),
+                        cell_type.const_zero(),
                         "is_zero",
                     )?;
                 self.builder.build_conditional_branch(is_zero, loop_end, loop_body)?;
 
                 self.builder.position_at_end(loop_body);
-                for inst in body {
-                    self.compile_instruction(inst, ptr, io)?;
-                }
-                self.builder.build_unconditional_branch(loop_check)?;
-
-                self.builder.position_at_end(loop_end);
+                loops.push((loop_check, loop_end));
             }
         }
         Ok(())
@@ -261,25 +875,32 @@ impl<'ctx> JITContext<'ctx> {
 }
 
 pub struct LLVM<'ctx> {
-    ir: Vec<BrainfuckIR>,
-    memory: Vec<u8>,
+    ir: Vec<BrainfuckNode>,
+    config: VMConfig,
+    memory: JitTape,
     io: IO,
+    cancel: CancelHandle,
     jit_context: Option<JITContext<'ctx>>,
 }
 
 impl VMInterface for LLVM<'_> {
-    fn new(ir: Vec<BrainfuckIR>, input: Box<dyn Read>, output: Box<dyn Write>) -> anyhow::Result<Self>
+    fn with_config(
+        ir: Vec<BrainfuckNode>,
+        input: Box<dyn Read>,
+        output: Box<dyn Write>,
+        config: VMConfig,
+    ) -> anyhow::Result<Self>
     where
         Self: Sized
     {
+        config.check_tape("the LLVM JIT", &[TapePolicy::Fixed, TapePolicy::Wrap], usize::MAX)?;
         Ok(Self {
+            memory: JitTape::new(&config, &ir)?,
             ir,
             jit_context: None,
-            memory: vec![0; MEMORY_SIZE],
-            io: IO {
-                input,
-                output,
-            },
+            config,
+            io: IO::new(input, output),
+            cancel: CancelHandle::default(),
         })
     }
 
@@ -289,17 +910,35 @@ impl VMInterface for LLVM<'_> {
 
         let clock = quanta::Clock::new();
 
+        // a suspended run is abandoned
+        self.io.suspended = None;
+        self.io.limit_output(None);
         let start = clock.now();
-        let _ = unsafe { func.call(self.memory.as_mut_ptr(), &mut self.io) };
+        let code = unsafe { self.memory.run(func.as_raw() as *const u8, &mut self.io) };
         let end = clock.now();
+        exit_status(code, &mut self.io, self.config.fuel)?;
 
         Ok(end - start)
     }
+
+    fn resume(&mut self) -> anyhow::Result<Stop> {
+        let func = self.jit_context.as_ref().ok_or_else(|| LLVMError::RunWithoutCompile)?
+            .jit_func.as_ref().ok_or_else(|| LLVMError::RunWithoutCompile)?;
+
+        // bf_jit_main continues where bf_suspend left it, if it did
+        self.io.limit_output(self.config.output_chunk);
+        let code = unsafe { self.memory.run(func.as_raw() as *const u8, &mut self.io) };
+        resume_status(code, &mut self.io, self.config.fuel)
+    }
+
+    fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
 }
 
 impl<'ctx> LLVM<'ctx> {
     pub fn compile(&mut self, context: &'ctx Context) -> anyhow::Result<()> {
-        self.jit_context = Some(JITContext::new(context)?);
+        self.jit_context = Some(JITContext::new(context, &self.config, self.cancel.clone())?);
         self.jit_context
             .as_mut()
             .ok_or_else(|| LLVMError::CouldNotCreateContext)?
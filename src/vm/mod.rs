@@ -1,47 +1,682 @@
 mod vm;
+mod bytecode;
 mod cranelift;
 mod llvm;
+mod guard;
+mod resume;
 
-use std::{io::{Read, Write}, time::Duration};
+use std::{
+    io::{BufWriter, ErrorKind, Read, Write},
+    sync::{atomic::{AtomicBool, Ordering}, Arc},
+    time::Duration,
+};
+use thiserror::Error;
 
-use crate::ir::BrainfuckIR;
+use crate::ir::{walk, BrainfuckIR, BrainfuckNode, Walk};
 
 pub trait VMInterface {
-    fn new(ir: Vec<BrainfuckIR>, input: Box<dyn Read>, output: Box<dyn Write>) -> anyhow::Result<Self>
+    fn new(ir: Vec<BrainfuckNode>, input: Box<dyn Read>, output: Box<dyn Write>) -> anyhow::Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::with_config(ir, input, output, VMConfig::default())
+    }
+    fn with_config(
+        ir: Vec<BrainfuckNode>,
+        input: Box<dyn Read>,
+        output: Box<dyn Write>,
+        config: VMConfig,
+    ) -> anyhow::Result<Self>
     where
         Self: Sized;
     fn run(&mut self) -> anyhow::Result<Duration>;
+    /// Run the program until it ends or a `,` would block, that is the input returns
+    /// `ErrorKind::WouldBlock`. A blocked run returns `Stop::NeedInput` and the next call
+    /// continues it at the same `,`, the output written so far is flushed. `Resumable` feeds
+    /// such an input.
+    ///
+    /// With `VMConfig::output_chunk` set the run also stops once it wrote that many bytes,
+    /// returning `Stop::OutputFull` after the flushed write. The next call continues after it.
+    ///
+    /// Returns `Stop::Done` once the program ended. A later call starts the program again, as
+    /// does `run`, which fails on a `,` that would block.
+    #[doc(hidden)]
+    fn resume(&mut self) -> anyhow::Result<Stop>;
+    /// A handle that stops `run` from another thread.
+    fn cancel_handle(&self) -> CancelHandle;
+}
+
+/// Stops a running program, from any thread. The program polls the handle at loop
+/// back-edges and stops with `RuntimeError::Cancelled`, so a program blocked on input
+/// only stops once the read returns.
+#[derive(Debug, Clone, Default)]
+pub struct CancelHandle(Arc<AtomicBool>);
+
+impl CancelHandle {
+    /// Stop the program, every later run stops at its first loop back-edge too.
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// The flag JIT code polls, a byte that is non-zero once cancelled. It stays valid as
+    /// long as the handle does.
+    pub(crate) fn flag(&self) -> *const AtomicBool {
+        Arc::as_ptr(&self.0)
+    }
+}
+
+/// Default tape size in cells
+pub const MEMORY_SIZE: usize = 4 * 1024 * 1024; // 4 Mi cells
+
+// return codes of the JIT compiled `bf_jit_main`
+pub(crate) const JIT_EXIT_OK: i64 = 0;
+pub(crate) const JIT_EXIT_OVERFLOW: i64 = 1;
+pub(crate) const JIT_EXIT_ENDLESS_SCAN: i64 = 2;
+pub(crate) const JIT_EXIT_IO_ERROR: i64 = 3;
+pub(crate) const JIT_EXIT_FUEL_EXHAUSTED: i64 = 4;
+pub(crate) const JIT_EXIT_CANCELLED: i64 = 5;
+/// A `,` would block, `bf_suspend` saved where the code stopped
+pub(crate) const JIT_EXIT_NEED_INPUT: i64 = 6;
+/// A write reached the output limit, `bf_suspend` saved where the code stopped after it
+pub(crate) const JIT_EXIT_OUTPUT_FULL: i64 = 7;
+
+/// What `bf_get` returns when reading failed, -1 is the end of input.
+pub(crate) const BF_GET_ERROR: i32 = -2;
+/// What `bf_get` returns when reading would block.
+pub(crate) const BF_GET_BLOCKED: i32 = -3;
+
+/// Turn the return code of `bf_jit_main` into the error the interpreter would raise, and
+/// flush the output. `fuel` is the fuel the code started with. The code doesn't say where
+/// it stopped, so unlike the interpreter's the error has no source position.
+pub(crate) fn exit_status(code: i64, io: &mut IO, fuel: Option<u64>) -> anyhow::Result<()> {
+    let status = match code {
+        JIT_EXIT_OK => Ok(()),
+        JIT_EXIT_OVERFLOW => Err(RuntimeError::Overflow.into()),
+        JIT_EXIT_ENDLESS_SCAN => Err(RuntimeError::EndlessScan.into()),
+        JIT_EXIT_FUEL_EXHAUSTED => match fuel {
+            Some(steps) => Err(RuntimeError::FuelExhausted { steps }.into()),
+            None => unreachable!("JIT code ran out of fuel without a limit"),
+        },
+        JIT_EXIT_CANCELLED => Err(RuntimeError::Cancelled.into()),
+        JIT_EXIT_IO_ERROR | JIT_EXIT_NEED_INPUT => {
+            // a `,` that would block is an error unless the run is resumed
+            io.suspended = None;
+            match io.error.take() {
+                Some(err) => Err(err.into()),
+                None => unreachable!("IO callback failed without an error"),
+            }
+        }
+        _ => unreachable!("unknown JIT exit code {}", code),
+    };
+    io.finish(status)
+}
+
+/// `exit_status` for `VMInterface::resume`, code that stopped at a `,` that would block is
+/// suspended instead of failing.
+pub(crate) fn resume_status(code: i64, io: &mut IO, fuel: Option<u64>) -> anyhow::Result<Stop> {
+    if code == JIT_EXIT_NEED_INPUT {
+        // the output was flushed before the read
+        io.error = None;
+        return Ok(Stop::NeedInput);
+    }
+    if code == JIT_EXIT_OUTPUT_FULL {
+        // and after the write
+        return Ok(Stop::OutputFull);
+    }
+    exit_status(code, io, fuel)?;
+    Ok(Stop::Done)
+}
+
+/// Number of `,` and `.` in `ir`, the points JIT code can be suspended at.
+pub(crate) fn count_resume_points(ir: &[BrainfuckNode]) -> usize {
+    walk(ir)
+        .filter(|step| matches!(
+            step,
+            Walk::Enter(BrainfuckNode { ir: BrainfuckIR::GetByte(_) | BrainfuckIR::PutByte(_) | BrainfuckIR::PutBytes(_), .. })
+        ))
+        .count()
+}
+
+/// How the machine is set up, shared by every backend.
+#[derive(Debug, Clone)]
+pub struct VMConfig {
+    pub cell_width: CellWidth,
+    /// Number of cells, the initial size of a growing tape
+    pub tape_size: usize,
+    pub tape_policy: TapePolicy,
+    /// The tape also has `tape_size` cells left of the start cell, and a growing tape grows
+    /// to the left as well
+    pub bidirectional: bool,
+    /// What `,` stores at the end of input
+    pub eof: EofPolicy,
+    /// How JIT code keeps the pointer on a fixed tape
+    pub bounds_checks: BoundsChecks,
+    /// Stop with `RuntimeError::FuelExhausted` after this many steps. The interpreters take
+    /// a step per instruction and per check of a loop condition, the JITs one per loop
+    /// iteration.
+    pub fuel: Option<u64>,
+    /// Stop `VMInterface::resume` once the program wrote this many bytes, `run` ignores it
+    pub output_chunk: Option<usize>,
+}
+
+impl Default for VMConfig {
+    fn default() -> Self {
+        Self {
+            cell_width: CellWidth::default(),
+            tape_size: MEMORY_SIZE,
+            tape_policy: TapePolicy::default(),
+            bidirectional: false,
+            eof: EofPolicy::default(),
+            bounds_checks: BoundsChecks::default(),
+            fuel: None,
+            output_chunk: None,
+        }
+    }
+}
+
+impl VMConfig {
+    /// Number of cells allocated up front, on both sides of the start cell.
+    pub fn tape_len(&self) -> usize {
+        if self.bidirectional {
+            self.tape_size.saturating_mul(2)
+        } else {
+            self.tape_size
+        }
+    }
+
+    /// Index of the cell the pointer starts at.
+    pub fn origin(&self) -> usize {
+        if self.bidirectional {
+            self.tape_size
+        } else {
+            0
+        }
+    }
+
+    /// Reject a tape `backend` can't run, `policies` are the tape policies it implements.
+    pub(crate) fn check_tape(
+        &self,
+        backend: &'static str,
+        policies: &[TapePolicy],
+        max_tape_size: usize,
+    ) -> Result<(), ConfigError> {
+        if self.tape_size == 0 {
+            return Err(ConfigError::EmptyTape);
+        }
+        if self.tape_len() > max_tape_size {
+            return Err(ConfigError::TapeTooLarge { backend, max: max_tape_size });
+        }
+        if !policies.contains(&self.tape_policy) {
+            return Err(ConfigError::UnsupportedPolicy { backend, policy: self.tape_policy });
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+    #[error("the tape needs at least one cell")]
+    EmptyTape,
+    #[error("{backend} supports tapes of at most {max} cells")]
+    TapeTooLarge { backend: &'static str, max: usize },
+    #[error("{backend} does not support the `{policy}` tape policy")]
+    UnsupportedPolicy { backend: &'static str, policy: TapePolicy },
+    #[error("guard pages are only supported on x86-64 Linux")]
+    GuardPagesUnsupported,
+    #[error("a tape between guard pages must fill whole pages of {page} bytes")]
+    UnalignedTape { page: usize },
+    #[error("the program moves the pointer {reach} cells without touching the tape, too far for guard pages")]
+    GuardTooLarge { reach: u64 },
+}
+
+/// What happens when the pointer leaves the tape.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum TapePolicy {
+    /// Stop with `RuntimeError::Overflow`
+    #[default]
+    Fixed,
+    /// Continue at the other end, the pointer is taken modulo the tape size
+    Wrap,
+    /// Extend the tape when the pointer moves past its right end, or past either end of a
+    /// bidirectional tape
+    Grow,
+}
+
+impl std::fmt::Display for TapePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TapePolicy::Fixed => write!(f, "fixed"),
+            TapePolicy::Wrap => write!(f, "wrap"),
+            TapePolicy::Grow => write!(f, "grow"),
+        }
+    }
+}
+
+impl std::str::FromStr for TapePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "fixed" => Ok(TapePolicy::Fixed),
+            "wrap" => Ok(TapePolicy::Wrap),
+            "grow" => Ok(TapePolicy::Grow),
+            _ => Err(format!("tape policy must be `fixed`, `wrap` or `grow`, not `{}`", s)),
+        }
+    }
+}
+
+/// How JIT code stops the pointer from leaving a fixed tape.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum BoundsChecks {
+    /// Compare the pointer against the ends of the tape before it moves
+    #[default]
+    Explicit,
+    /// Map the tape between guard pages and turn the fault of a step onto one into
+    /// `RuntimeError::Overflow`, the tape must fill whole pages
+    GuardPages,
+    /// No checks, only safe for programs known to stay on the tape
+    Off,
+}
+
+impl std::fmt::Display for BoundsChecks {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BoundsChecks::Explicit => write!(f, "explicit"),
+            BoundsChecks::GuardPages => write!(f, "guard-pages"),
+            BoundsChecks::Off => write!(f, "off"),
+        }
+    }
+}
+
+impl std::str::FromStr for BoundsChecks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "explicit" => Ok(BoundsChecks::Explicit),
+            "guard-pages" => Ok(BoundsChecks::GuardPages),
+            "off" => Ok(BoundsChecks::Off),
+            _ => Err(format!("bounds checks must be `explicit`, `guard-pages` or `off`, not `{}`", s)),
+        }
+    }
+}
+
+/// What `,` does once the input is exhausted.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum EofPolicy {
+    /// Store 0
+    #[default]
+    Zero,
+    /// Store -1, all bits set at the cell width, so 255 on 8-bit cells
+    MinusOne,
+    /// Leave the cell as it is
+    Unchanged,
+}
+
+impl EofPolicy {
+    /// The new value of a cell holding `old` after `,` read `byte`, `None` at the end of input.
+    pub fn store(self, byte: Option<u8>, old: u32, width: CellWidth) -> u32 {
+        match (byte, self) {
+            (Some(byte), _) => byte as u32,
+            (None, EofPolicy::Zero) => 0,
+            (None, EofPolicy::MinusOne) => width.truncate(u32::MAX),
+            (None, EofPolicy::Unchanged) => old,
+        }
+    }
+}
+
+impl std::fmt::Display for EofPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EofPolicy::Zero => write!(f, "0"),
+            EofPolicy::MinusOne => write!(f, "-1"),
+            EofPolicy::Unchanged => write!(f, "unchanged"),
+        }
+    }
+}
+
+impl std::str::FromStr for EofPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "0" => Ok(EofPolicy::Zero),
+            "-1" | "255" => Ok(EofPolicy::MinusOne),
+            "unchanged" => Ok(EofPolicy::Unchanged),
+            _ => Err(format!("EOF policy must be `0`, `-1` (or `255`) or `unchanged`, not `{}`", s)),
+        }
+    }
+}
+
+/// Size of a tape cell, arithmetic wraps around at the cell width.
+///
+/// Output writes the low 8 bits of a cell and input stores the byte read zero-extended,
+/// so programs written for 8-bit cells behave the same on wider cells as long as they
+/// don't rely on overflow.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq)]
+pub enum CellWidth {
+    #[default]
+    U8,
+    U16,
+    U32,
 }
 
-pub const MEMORY_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
+impl CellWidth {
+    pub fn bits(self) -> u32 {
+        match self {
+            CellWidth::U8 => 8,
+            CellWidth::U16 => 16,
+            CellWidth::U32 => 32,
+        }
+    }
+
+    pub fn bytes(self) -> usize {
+        self.bits() as usize / 8
+    }
+
+    /// `value` modulo 2^bits.
+    pub fn truncate(self, value: u32) -> u32 {
+        match self {
+            CellWidth::U8 => value as u8 as u32,
+            CellWidth::U16 => value as u16 as u32,
+            CellWidth::U32 => value,
+        }
+    }
+}
+
+impl std::fmt::Display for CellWidth {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.bits())
+    }
+}
 
+impl std::str::FromStr for CellWidth {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "8" => Ok(CellWidth::U8),
+            "16" => Ok(CellWidth::U16),
+            "32" => Ok(CellWidth::U32),
+            _ => Err(format!("cell width must be 8, 16 or 32 bits, not `{}`", s)),
+        }
+    }
+}
+
+/// An integer type the interpreter can use as tape cell.
+pub(crate) trait Cell: Copy + Default + Eq + 'static {
+    /// Truncating conversion, IR constants are kept modulo 2^32.
+    fn from_u32(value: u32) -> Self;
+    fn to_u32(self) -> u32;
+
+    /// Find the first zero cell starting at `ptr` and stepping by `stride`,
+    /// `None` if the scan walks off the tape.
+    fn scan(memory: &[Self], ptr: usize, stride: isize) -> Option<usize> {
+        let zero = Self::default();
+        let mut ptr = ptr;
+        while *memory.get(ptr)? != zero {
+            ptr = ptr.checked_add_signed(stride)?;
+        }
+        Some(ptr)
+    }
+}
+
+impl Cell for u8 {
+    fn from_u32(value: u32) -> Self {
+        value as u8
+    }
+
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+
+    fn scan(memory: &[Self], ptr: usize, stride: isize) -> Option<usize> {
+        match stride {
+            1 => memchr::memchr(0, memory.get(ptr..)?).map(|found| ptr + found),
+            -1 => memchr::memrchr(0, memory.get(..=ptr)?),
+            _ => {
+                let mut ptr = ptr;
+                while *memory.get(ptr)? != 0 {
+                    ptr = ptr.checked_add_signed(stride)?;
+                }
+                Some(ptr)
+            }
+        }
+    }
+}
+
+impl Cell for u16 {
+    fn from_u32(value: u32) -> Self {
+        value as u16
+    }
+
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+}
+
+impl Cell for u32 {
+    fn from_u32(value: u32) -> Self {
+        value
+    }
+
+    fn to_u32(self) -> u32 {
+        self
+    }
+}
+
+/// Like `Cell::scan` on a tape whose ends are joined, `None` if no cell the scan
+/// visits is zero so it would go round forever.
+pub(crate) fn scan_wrapping<C: Cell>(memory: &[C], ptr: usize, stride: isize) -> Option<usize> {
+    let len = memory.len() as isize;
+    let mut ptr = ptr as isize;
+    // the scan is back where it started after at most `len` steps
+    for _ in 0..len {
+        if memory[ptr as usize] == C::default() {
+            return Some(ptr as usize);
+        }
+        ptr = (ptr + stride).rem_euclid(len);
+    }
+    None
+}
+
+/// Zeroed memory for `cells` cells of `width`, backed by `u32` so every cell is aligned
+/// no matter the width.
+pub(crate) fn alloc_tape(cells: usize, width: CellWidth) -> Vec<u32> {
+    vec![0; (cells * width.bytes()).div_ceil(4)]
+}
+
+/// The streams a program runs on, output is buffered until the program reads or stops.
 pub struct IO {
     pub input: Box<dyn Read>,
-    pub output: Box<dyn Write>,
+    pub output: BufWriter<Box<dyn Write>>,
+    /// The error a callback stopped the JIT code with
+    pub error: Option<std::io::Error>,
+    /// Where JIT code waits for input, until it is resumed
+    pub(crate) suspended: Option<Suspended>,
+    /// Bytes written since `limit_output`, and how many make the output full
+    written: usize,
+    output_limit: Option<usize>,
+}
+
+/// What `bf_suspend` saves of JIT code stopped at a `,` that would block or after a `.`
+/// that filled the output.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Suspended {
+    /// The `,` or `.`, numbered from 1 in the order the code was generated
+    point: i64,
+    /// How the backend keeps the pointer, an index or an address
+    pointer: i64,
+    fuel: i64,
+}
+
+impl IO {
+    pub fn new(input: Box<dyn Read>, output: Box<dyn Write>) -> Self {
+        Self { input, output: BufWriter::new(output), error: None, suspended: None, written: 0, output_limit: None }
+    }
+
+    /// Count the output from here on, a write that reaches `limit` bytes fills the output.
+    pub(crate) fn limit_output(&mut self, limit: Option<usize>) {
+        self.written = 0;
+        self.output_limit = limit;
+    }
+
+    /// Write `bytes`, `true` if that filled the output. A full output is flushed.
+    pub(crate) fn write(&mut self, bytes: &[u8]) -> std::io::Result<bool> {
+        self.output.write_all(bytes)?;
+        self.written += bytes.len();
+        match self.output_limit {
+            Some(limit) if self.written >= limit => {
+                self.output.flush()?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Read one byte, `None` at the end of input. The output is flushed first so a prompt
+    /// shows before the program waits.
+    pub(crate) fn read_byte(&mut self) -> std::io::Result<Option<u8>> {
+        self.output.flush()?;
+        read_byte(self.input.as_mut())
+    }
+
+    /// Flush the output after a run that ended with `result`. The error of the run, if any,
+    /// is returned rather than one from flushing.
+    pub(crate) fn finish<T>(&mut self, result: anyhow::Result<T>) -> anyhow::Result<T> {
+        let flushed = self.output.flush();
+        let value = result?;
+        flushed?;
+        Ok(value)
+    }
+
+    /// Keep `err` for `run` to return, the JIT code stops on the returned exit code.
+    fn fail(&mut self, err: std::io::Error) -> i64 {
+        self.error = Some(err);
+        JIT_EXIT_IO_ERROR
+    }
+}
+
+/// Read one byte, `None` at the end of input.
+fn read_byte(input: &mut dyn Read) -> std::io::Result<Option<u8>> {
+    let mut byte = [0u8; 1];
+    match input.read_exact(&mut byte) {
+        Ok(()) => Ok(Some(byte[0])),
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => Ok(None),
+        Err(err) => Err(err),
+    }
 }
 
 #[no_mangle]
-pub extern "C" fn bf_put(context: *mut IO, ch: u8) {
+pub extern "C" fn bf_put(context: *mut IO, ch: u8) -> i64 {
     unsafe {
         // get IO
         let ctx = &mut *context;
-        // write to output
-        ctx.output.write_all(&[ch]).unwrap();
+        // write to output, the result is the exit code the JIT code stops with if it isn't OK
+        match ctx.write(&[ch]) {
+            Ok(false) => JIT_EXIT_OK,
+            Ok(true) => JIT_EXIT_OUTPUT_FULL,
+            Err(err) => ctx.fail(err),
+        }
     }
 }
 
 #[no_mangle]
-pub extern "C" fn bf_get(context: *mut IO) -> u8 {
+pub(crate) extern "C" fn bf_write(context: *mut IO, bytes: *const u8, len: usize) -> i64 {
+    // SAFETY: only called from JIT code, with the `IO` of the running VM and `len` bytes of
+    // output the code was compiled with, which live as long as the code
     unsafe {
         // get IO
         let ctx = &mut *context;
-        // read from input
-        let mut buffer = [0u8; 1];
-        ctx.input.read(buffer.as_mut()).unwrap();
-        buffer[0]
+        // write all bytes at once
+        match ctx.write(std::slice::from_raw_parts(bytes, len)) {
+            Ok(false) => JIT_EXIT_OK,
+            Ok(true) => JIT_EXIT_OUTPUT_FULL,
+            Err(err) => ctx.fail(err),
+        }
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn bf_get(context: *mut IO) -> i32 {
+    unsafe {
+        // get IO
+        let ctx = &mut *context;
+        // read from input, -1 at the end of input and the JIT code applies the EOF policy
+        match ctx.read_byte() {
+            Ok(byte) => byte.map_or(-1, i32::from),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                // the error for `run`, the JIT code suspends itself
+                ctx.error = Some(err);
+                BF_GET_BLOCKED
+            }
+            Err(err) => {
+                ctx.fail(err);
+                BF_GET_ERROR
+            }
+        }
+    }
+}
+
+#[no_mangle]
+pub(crate) extern "C" fn bf_suspend(context: *mut IO, point: i64, pointer: i64, fuel: i64) {
+    // SAFETY: only called from JIT code, with the `IO` of the running VM
+    unsafe {
+        // get IO
+        let ctx = &mut *context;
+        // keep what the JIT code needs to continue at `point`
+        ctx.suspended = Some(Suspended { point, pointer, fuel });
+    }
+}
+
+#[no_mangle]
+pub(crate) extern "C" fn bf_resume(context: *mut IO, saved: *mut i64) -> i64 {
+    // SAFETY: only called from JIT code, with the `IO` of the running VM and its two slot
+    // save area on the stack of `bf_jit_main`
+    unsafe {
+        // get IO
+        let ctx = &mut *context;
+        // 0 starts from the beginning, otherwise the pointer and fuel go to `saved[0..2]`
+        match ctx.suspended.take() {
+            Some(suspended) => {
+                *saved = suspended.pointer;
+                *saved.add(1) = suspended.fuel;
+                suspended.point
+            }
+            None => 0,
+        }
+    }
+}
+
+#[no_mangle]
+pub(crate) extern "C" fn bf_scan(memory: *const u8, len: usize, ptr: usize, stride: isize, width: usize, wrap: bool) -> isize {
+    fn scan<C: Cell>(memory: *const u8, len: usize, ptr: usize, stride: isize, wrap: bool) -> isize {
+        // get the whole tape, `len` counts cells
+        // SAFETY: only called from JIT code, with the tape of the running VM, its length in
+        // cells and the width of its cells, the tape is aligned for every width
+        let memory = unsafe { std::slice::from_raw_parts(memory as *const C, len) };
+        // a negative result is the negated exit code the JIT code returns
+        if wrap {
+            scan_wrapping(memory, ptr, stride).map_or(-JIT_EXIT_ENDLESS_SCAN as isize, |found| found as isize)
+        } else {
+            C::scan(memory, ptr, stride).map_or(-JIT_EXIT_OVERFLOW as isize, |found| found as isize)
+        }
+    }
+
+    match width {
+        1 => scan::<u8>(memory, len, ptr, stride, wrap),
+        2 => scan::<u16>(memory, len, ptr, stride, wrap),
+        _ => scan::<u32>(memory, len, ptr, stride, wrap),
     }
 }
 
-pub use vm::VM;
+pub use vm::{RuntimeError, VM};
+pub use bytecode::VMBytecode;
 pub use cranelift::VMCranelift;
 pub use llvm::LLVM;
+pub use resume::{Resumable, Yield, OUTPUT_CHUNK};
+pub(crate) use resume::Stop;
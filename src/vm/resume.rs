@@ -0,0 +1,180 @@
+//! Running a program from event-driven code.
+//!
+//! `Resumable` gives a VM an input that never blocks: a `,` with nothing to read suspends
+//! the program and `resume` returns `Yield::NeedInput`. The caller feeds more input and
+//! resumes, the program continues at the same `,`. A program that writes a lot is suspended
+//! the same way every `OUTPUT_CHUNK` bytes, so its output comes back in bounded chunks.
+//! `Resumable::run_async` does that with async streams.
+
+use std::{
+    cell::RefCell,
+    collections::VecDeque,
+    io::{self, ErrorKind, Read, Write},
+    rc::Rc,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::ir::BrainfuckNode;
+use crate::vm::{VMConfig, VMInterface};
+
+/// Bytes of output a `Resumable` collects before it suspends the program, unless the config
+/// sets `output_chunk`.
+pub const OUTPUT_CHUNK: usize = 8 * 1024;
+
+/// Why a resumed program returned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Yield {
+    /// The program waits for input
+    NeedInput,
+    /// The program wrote these bytes
+    Output(Vec<u8>),
+    /// The program ended
+    Done,
+}
+
+/// Why `VMInterface::resume` returned, `Resumable` turns it into a `Yield`. It can't be named
+/// outside the crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    /// A `,` would block
+    NeedInput,
+    /// The program wrote `VMConfig::output_chunk` bytes, they were flushed
+    OutputFull,
+    /// The program ended
+    Done,
+}
+
+/// Input fed by the caller, reading it would block while it is empty and still open.
+#[derive(Default)]
+struct Pending {
+    bytes: VecDeque<u8>,
+    closed: bool,
+}
+
+#[derive(Clone, Default)]
+struct Feed(Rc<RefCell<Pending>>);
+
+impl Read for Feed {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut pending = self.0.borrow_mut();
+        if pending.bytes.is_empty() && !pending.closed {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        pending.bytes.read(buf)
+    }
+}
+
+/// Output kept until `resume` returns it.
+#[derive(Clone, Default)]
+struct Collect(Rc<RefCell<Vec<u8>>>);
+
+impl Write for Collect {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A program that runs until it needs input, instead of blocking until there is some.
+pub struct Resumable<V> {
+    vm: V,
+    input: Feed,
+    output: Collect,
+    /// the program ended or failed
+    done: bool,
+}
+
+impl<V: VMInterface> Resumable<V> {
+    pub fn new(ir: Vec<BrainfuckNode>, mut config: VMConfig) -> anyhow::Result<Self> {
+        config.output_chunk.get_or_insert(OUTPUT_CHUNK);
+        let input = Feed::default();
+        let output = Collect::default();
+        let vm = V::with_config(ir, Box::new(input.clone()), Box::new(output.clone()), config)?;
+        Ok(Self { vm, input, output, done: false })
+    }
+
+    /// The VM the program runs on, a JIT has to be compiled before the first `resume`.
+    pub fn vm(&mut self) -> &mut V {
+        &mut self.vm
+    }
+
+    /// Append `bytes` to the input.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.input.0.borrow_mut().bytes.extend(bytes);
+    }
+
+    /// End the input, `,` reads the end of input once the bytes fed so far are read.
+    pub fn close_input(&mut self) {
+        self.input.0.borrow_mut().closed = true;
+    }
+
+    /// Run the program until it needs input, ends, or wrote `output_chunk` bytes. The output
+    /// it wrote is returned first as `Yield::Output`, the next call returns `Yield::NeedInput`
+    /// or `Yield::Done`, or continues a program that filled the output.
+    ///
+    /// An error ends the program, the output written before it is returned by the next call.
+    pub fn resume(&mut self) -> anyhow::Result<Yield> {
+        if !self.done {
+            let result = self.vm.resume();
+            self.done = !matches!(result, Ok(Stop::NeedInput | Stop::OutputFull));
+            result?;
+        }
+        let output = std::mem::take(&mut *self.output.0.borrow_mut());
+        if !output.is_empty() {
+            return Ok(Yield::Output(output));
+        }
+        Ok(if self.done { Yield::Done } else { Yield::NeedInput })
+    }
+
+    /// Run the program on async streams until it ends. It waits on `input` whenever it needs
+    /// input, and every chunk of output is written to `output` and flushed as the program
+    /// stops for it. In between the program runs on the calling task, dropping the future
+    /// stops it at the next read or chunk of output.
+    ///
+    /// The input fed so far is read first, and the input is closed once `input` ends.
+    ///
+    /// The future is not `Send`: the VM, its buffers and compiled code stay on the thread they
+    /// were made on. On a multi-threaded runtime run it on a `tokio::task::LocalSet`, with
+    /// `spawn_local` instead of `tokio::spawn`.
+    pub async fn run_async<R, W>(&mut self, mut input: R, mut output: W) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let mut buf = [0u8; 8 * 1024];
+        loop {
+            let yielded = match self.resume() {
+                Ok(yielded) => yielded,
+                Err(err) => {
+                    // the error of the run is returned rather than one from writing what the
+                    // program wrote before it
+                    if let Ok(Yield::Output(bytes)) = self.resume() {
+                        let _ = output.write_all(&bytes).await;
+                    }
+                    let _ = output.flush().await;
+                    return Err(err);
+                }
+            };
+            match yielded {
+                Yield::Output(bytes) => {
+                    // the other end may wait for the output before it sends more
+                    output.write_all(&bytes).await?;
+                    output.flush().await?;
+                }
+                Yield::NeedInput => {
+                    match input.read(&mut buf).await? {
+                        0 => self.close_input(),
+                        n => self.feed(&buf[..n]),
+                    }
+                }
+                Yield::Done => break,
+            }
+        }
+        output.flush().await?;
+        Ok(())
+    }
+}
@@ -1,8 +1,8 @@
-use std::{io::{Read, Write}, time::Duration};
+use std::{io::{ErrorKind, Read, Write}, time::Duration};
 use thiserror::Error;
 
-use crate::ir::BrainfuckIR;
-use crate::vm::{VMInterface, MEMORY_SIZE};
+use crate::ir::{BrainfuckIR, BrainfuckNode};
+use crate::vm::{scan_wrapping, CancelHandle, Cell, CellWidth, EofPolicy, TapePolicy, VMConfig, VMInterface, Stop, IO};
 
 #[derive(Debug, Error)]
 pub enum RuntimeError {
@@ -10,85 +10,343 @@ pub enum RuntimeError {
     IO(#[from] std::io::Error),
     #[error("overflow")]
     Overflow,
+    #[error("scan loop never finds a zero cell")]
+    EndlessScan,
+    #[error("fuel exhausted after {steps} steps")]
+    FuelExhausted { steps: u64 },
+    #[error("cancelled")]
+    Cancelled,
 }
 
-struct VMContext {
-    memory: Box<[u8]>,
-    input: Box<dyn Read>,
-    output: Box<dyn Write>,
+/// The tape and streams of the interpreters.
+pub(super) struct VMContext<C: Cell> {
+    pub(super) memory: Vec<C>,
+    policy: TapePolicy,
+    /// a growing tape also grows to the left
+    bidirectional: bool,
+    pub(super) cell_width: CellWidth,
+    pub(super) eof: EofPolicy,
+    pub(super) io: IO,
+    /// steps left, and the limit they started from
+    fuel: u64,
+    fuel_limit: u64,
+    cancel: CancelHandle,
+    /// why the run stopped early: `Stop::NeedInput` at a `,` that would have blocked, or
+    /// `Stop::OutputFull` after a write that filled the output
+    pub(super) stopped: Option<Stop>,
+}
+
+/// The interpreter for one cell type, picked once when the VM is created.
+trait Interpreter {
+    /// Run `ir` from the node `at` leads to, the start of the program if it is empty.
+    ///
+    /// `at` holds the index of a node in every block from the outermost one in, each but the
+    /// last at the loop the next block is the body of. A run that stops leaves it at the node
+    /// to continue with, see `take_stopped`.
+    fn run(&mut self, ir: &[BrainfuckNode], ptr: &mut usize, at: &mut Vec<usize>) -> anyhow::Result<()>;
+    /// Why the last run stopped early, if it did.
+    fn take_stopped(&mut self) -> Option<Stop>;
+    fn io(&mut self) -> &mut IO;
 }
 
 pub struct VM {
-    ir: Vec<BrainfuckIR>,
-    context: VMContext,
+    ir: Vec<BrainfuckNode>,
+    context: Box<dyn Interpreter>,
+    origin: usize,
+    cancel: CancelHandle,
+    /// the pointer of a suspended run and where it goes on, see `Interpreter::run`
+    suspended: Option<(usize, Vec<usize>)>,
+    output_chunk: Option<usize>,
 }
 
 impl VMInterface for VM {
-    fn new(
-        ir: Vec<BrainfuckIR>,
+    fn with_config(
+        ir: Vec<BrainfuckNode>,
         input: Box<dyn Read>,
         output: Box<dyn Write>,
+        config: VMConfig,
     ) -> anyhow::Result<Self> {
-        let memory = vec![0; MEMORY_SIZE].into_boxed_slice();
+        config.check_tape("the interpreter", &[TapePolicy::Fixed, TapePolicy::Wrap, TapePolicy::Grow], usize::MAX)?;
+        let cancel = CancelHandle::default();
+        let context: Box<dyn Interpreter> = match config.cell_width {
+            CellWidth::U8 => Box::new(VMContext::<u8>::new(&config, input, output, cancel.clone())),
+            CellWidth::U16 => Box::new(VMContext::<u16>::new(&config, input, output, cancel.clone())),
+            CellWidth::U32 => Box::new(VMContext::<u32>::new(&config, input, output, cancel.clone())),
+        };
 
         Ok(Self {
             ir,
-            context: VMContext {
-                memory,
-                input,
-                output,
-            }
+            context,
+            origin: config.origin(),
+            cancel,
+            suspended: None,
+            output_chunk: config.output_chunk,
         })
     }
 
     fn run(&mut self) -> anyhow::Result<Duration> {
         let clock = quanta::Clock::new();
 
+        // a suspended run is abandoned, and a `,` that would block is an error
+        self.suspended = None;
+        self.context.io().limit_output(None);
         let start = clock.now();
-        let mut ptr = 0;
-        self.context.run_block(&self.ir, &mut ptr)?;
+        let mut ptr = self.origin;
+        let result = self.context.run(&self.ir, &mut ptr, &mut Vec::new());
         let end = clock.now();
+        self.context.take_stopped();
+        self.context.io().finish(result)?;
 
         Ok(end - start)
     }
+
+    fn resume(&mut self) -> anyhow::Result<Stop> {
+        self.context.io().limit_output(self.output_chunk);
+        let (mut ptr, mut at) = self.suspended.take().unwrap_or((self.origin, Vec::new()));
+        let result = self.context.run(&self.ir, &mut ptr, &mut at);
+        if let Some(stopped) = self.context.take_stopped() {
+            // the output was flushed before the read or after the write
+            self.suspended = Some((ptr, at));
+            return Ok(stopped);
+        }
+        self.context.io().finish(result)?;
+
+        Ok(Stop::Done)
+    }
+
+    fn cancel_handle(&self) -> CancelHandle {
+        self.cancel.clone()
+    }
 }
 
-impl VMContext {
-    fn run_block(&mut self, block: &[BrainfuckIR], ptr: &mut usize) -> anyhow::Result<()> {
-        let mut pc = 0usize;
-        while pc < block.len() {
-            match &block[pc] {
-                BrainfuckIR::AddVal(val) => self.memory[*ptr] = self.memory[*ptr].wrapping_add(*val),
-                BrainfuckIR::SubVal(val) => self.memory[*ptr] = self.memory[*ptr].wrapping_sub(*val),
-                BrainfuckIR::PtrMovRight(val) => {
-                    let new_ptr = (*ptr as isize).wrapping_add(*val as isize);
-                    if !(0..self.memory.len() as isize).contains(&new_ptr) {
-                        return Err(RuntimeError::Overflow.into());
-                    }
-                    *ptr = new_ptr as usize;
+impl<C: Cell> Interpreter for VMContext<C> {
+    fn run(&mut self, ir: &[BrainfuckNode], ptr: &mut usize, at: &mut Vec<usize>) -> anyhow::Result<()> {
+        // the blocks being run, innermost last, with the index of the node each one is at
+        let mut frames = vec![(ir, at.first().copied().unwrap_or(0))];
+        for &pc in at.iter().skip(1) {
+            let (block, parent) = frames[frames.len() - 1];
+            let BrainfuckIR::Loop(body) = &block[parent].ir else {
+                unreachable!("a stopped run is inside every loop on its way");
+            };
+            frames.push((body, pc));
+        }
+
+        let result = self.run_frames(&mut frames, ptr);
+        at.clear();
+        if let Some(stopped) = self.stopped {
+            at.extend(frames.iter().map(|&(_, pc)| pc));
+            // a `,` runs again once there is input, a `.` already wrote
+            if stopped == Stop::OutputFull {
+                *at.last_mut().unwrap() += 1;
+            }
+        }
+        result
+    }
+
+    fn take_stopped(&mut self) -> Option<Stop> {
+        self.stopped.take()
+    }
+
+    fn io(&mut self) -> &mut IO {
+        &mut self.io
+    }
+}
+
+impl<C: Cell> VMContext<C> {
+    /// Run the innermost of `frames` from the node it is at until the outermost one ends, an
+    /// error leaves every frame at the node that failed.
+    fn run_frames(&mut self, frames: &mut Vec<(&[BrainfuckNode], usize)>, ptr: &mut usize) -> anyhow::Result<()> {
+        loop {
+            let last = frames.len() - 1;
+            let (block, pc) = frames[last];
+            let Some(node) = block.get(pc) else {
+                if last == 0 {
+                    return Ok(());
                 }
-                BrainfuckIR::PtrMovLeft(val) => {
-                    let new_ptr = (*ptr as isize).wrapping_sub(*val as isize);
-                    if !(0..self.memory.len() as isize).contains(&new_ptr) {
-                        return Err(RuntimeError::Overflow.into());
+                // the end of an iteration, the loop checks its condition again
+                frames.pop();
+                let (block, pc) = frames[last - 1];
+                // the back-edge of the loop, where a run can be cancelled
+                self.poll_cancel().map_err(|err| err.context(format!("at {}", block[pc].span.start)))?;
+                continue;
+            };
+            // report where in the source a failing instruction came from
+            let at = |err: anyhow::Error| err.context(format!("at {}", node.span.start));
+            match &node.ir {
+                BrainfuckIR::Loop(body) => {
+                    // every check of the condition is a step
+                    self.step().map_err(at)?;
+                    if self.memory[*ptr] == C::default() {
+                        frames[last].1 += 1;
+                    } else {
+                        frames.push((body, 0));
                     }
-                    *ptr = new_ptr as usize;
-                }
-                BrainfuckIR::PutByte => {
-                    self.output.write_all(&self.memory[*ptr..=*ptr])?;
                 }
-                BrainfuckIR::GetByte => {
-                    let mut byte: [u8; 1] = [0; 1];
-                    self.input.read_exact(&mut byte)?;
-                    self.memory[*ptr] = byte[0];
+                inst => {
+                    self.step().and_then(|()| self.run_instruction(inst, ptr)).map_err(at)?;
+                    frames[last].1 += 1;
                 }
-                BrainfuckIR::Loop(loop_block) => {
-                    while self.memory[*ptr] != 0 {
-                        self.run_block(loop_block, ptr)?;
-                    }
+            }
+        }
+    }
+
+    pub(super) fn new(config: &VMConfig, input: Box<dyn Read>, output: Box<dyn Write>, cancel: CancelHandle) -> Self {
+        Self {
+            memory: vec![C::default(); config.tape_len()],
+            policy: config.tape_policy,
+            bidirectional: config.bidirectional,
+            cell_width: config.cell_width,
+            eof: config.eof,
+            io: IO::new(input, output),
+            fuel: config.fuel.unwrap_or(u64::MAX),
+            fuel_limit: config.fuel.unwrap_or(u64::MAX),
+            cancel,
+            stopped: None,
+        }
+    }
+
+    /// Take a step, `RuntimeError::FuelExhausted` once there is no fuel left.
+    #[inline(always)]
+    pub(super) fn step(&mut self) -> anyhow::Result<()> {
+        if self.fuel == 0 {
+            return Err(RuntimeError::FuelExhausted { steps: self.fuel_limit }.into());
+        }
+        self.fuel -= 1;
+        Ok(())
+    }
+
+    /// `RuntimeError::Cancelled` once the run has been cancelled.
+    #[inline(always)]
+    pub(super) fn poll_cancel(&self) -> anyhow::Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(RuntimeError::Cancelled.into());
+        }
+        Ok(())
+    }
+
+    /// Read a byte for `,`, `None` at the end of input. A read that would block stops the
+    /// run and gives the step of the `,` back, so it can run again once there is input.
+    pub(super) fn read(&mut self) -> anyhow::Result<Option<u8>> {
+        match self.io.read_byte() {
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                self.fuel += 1;
+                self.stopped = Some(Stop::NeedInput);
+                Err(err.into())
+            }
+            result => Ok(result?),
+        }
+    }
+
+    /// Write `bytes` for `.`. A write that fills the output stops the run after it.
+    pub(super) fn write(&mut self, bytes: &[u8]) -> anyhow::Result<()> {
+        if self.io.write(bytes)? {
+            // unwinds the run like a `,` that would block
+            self.stopped = Some(Stop::OutputFull);
+            return Err(std::io::Error::from(ErrorKind::WouldBlock).into());
+        }
+        Ok(())
+    }
+
+    /// Index of the cell `offset` away from `ptr`.
+    fn cell(&mut self, ptr: &mut usize, offset: i32) -> anyhow::Result<usize> {
+        self.index(ptr, offset as isize)
+    }
+
+    /// Index of the cell `delta` away from `ptr` as the tape policy says. Growing the tape
+    /// to the left moves every cell, `ptr` is kept on the cell it pointed at.
+    pub(super) fn index(&mut self, ptr: &mut usize, delta: isize) -> anyhow::Result<usize> {
+        let len = self.memory.len() as isize;
+        let index = (*ptr as isize).wrapping_add(delta);
+        match self.policy {
+            _ if (0..len).contains(&index) => Ok(index as usize),
+            TapePolicy::Wrap => Ok(index.rem_euclid(len) as usize),
+            TapePolicy::Grow if index >= len => {
+                // at least double the tape so growing stays cheap
+                let new_len = (index as usize + 1).max(self.memory.len() * 2);
+                self.memory.resize(new_len, C::default());
+                Ok(index as usize)
+            }
+            TapePolicy::Grow if self.bidirectional => {
+                let shift = index.unsigned_abs().max(self.memory.len());
+                self.memory.splice(0..0, std::iter::repeat_n(C::default(), shift));
+                *ptr += shift;
+                Ok((index + shift as isize) as usize)
+            }
+            _ => Err(RuntimeError::Overflow.into()),
+        }
+    }
+
+    /// Find the first zero cell starting at `ptr` and stepping by `stride`.
+    pub(super) fn scan(&mut self, ptr: &mut usize, stride: isize) -> anyhow::Result<usize> {
+        if self.policy == TapePolicy::Wrap {
+            return Ok(scan_wrapping(&self.memory, *ptr, stride).ok_or(RuntimeError::EndlessScan)?);
+        }
+        match C::scan(&self.memory, *ptr, stride) {
+            Some(found) => Ok(found),
+            // every cell past the end of a growing tape is zero, stop at the first one
+            None if self.policy == TapePolicy::Grow && (stride > 0 || self.bidirectional) => {
+                let steps = if stride > 0 {
+                    (self.memory.len() - *ptr).div_ceil(stride as usize)
+                } else {
+                    *ptr / stride.unsigned_abs() + 1
+                };
+                self.index(ptr, steps as isize * stride)
+            }
+            None => Err(RuntimeError::Overflow.into()),
+        }
+    }
+
+    fn run_instruction(&mut self, inst: &BrainfuckIR, ptr: &mut usize) -> anyhow::Result<()> {
+        match inst {
+            BrainfuckIR::AddVal(val, offset) => {
+                let cell = self.cell(ptr, *offset)?;
+                self.memory[cell] = C::from_u32(self.memory[cell].to_u32().wrapping_add(*val));
+            }
+            BrainfuckIR::SubVal(val, offset) => {
+                let cell = self.cell(ptr, *offset)?;
+                self.memory[cell] = C::from_u32(self.memory[cell].to_u32().wrapping_sub(*val));
+            }
+            BrainfuckIR::SetVal(val, offset) => {
+                let cell = self.cell(ptr, *offset)?;
+                self.memory[cell] = C::from_u32(*val);
+            }
+            BrainfuckIR::MulAdd { src, offset, factor } => {
+                // the loop this came from doesn't run on a zero cell, so it can't overflow either
+                let src = self.cell(ptr, *src)?;
+                let val = self.memory[src].to_u32();
+                if val != 0 {
+                    let target = self.cell(ptr, *offset)?;
+                    let product = val.wrapping_mul(*factor);
+                    self.memory[target] = C::from_u32(self.memory[target].to_u32().wrapping_add(product));
                 }
             }
-            pc += 1;
+            BrainfuckIR::PtrMovRight(val) => {
+                *ptr = self.index(ptr, *val as isize)?;
+            }
+            BrainfuckIR::PtrMovLeft(val) => {
+                *ptr = self.index(ptr, -(*val as isize))?;
+            }
+            BrainfuckIR::ScanRight(n) => {
+                *ptr = self.scan(ptr, *n as isize)?;
+            }
+            BrainfuckIR::ScanLeft(n) => {
+                *ptr = self.scan(ptr, -(*n as isize))?;
+            }
+            BrainfuckIR::PutByte(offset) => {
+                // only the low byte of a wide cell is written
+                let cell = self.cell(ptr, *offset)?;
+                self.write(&[self.memory[cell].to_u32() as u8])?;
+            }
+            BrainfuckIR::PutBytes(bytes) => {
+                self.write(bytes)?;
+            }
+            BrainfuckIR::GetByte(offset) => {
+                let cell = self.cell(ptr, *offset)?;
+                let byte = self.read()?;
+                let val = self.eof.store(byte, self.memory[cell].to_u32(), self.cell_width);
+                self.memory[cell] = C::from_u32(val);
+            }
+            BrainfuckIR::Loop(_) => unreachable!("loops are handled by run_block"),
         }
         Ok(())
     }
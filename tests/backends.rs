@@ -0,0 +1,870 @@
+//! Differential tests: every backend must produce the same output at every optimization level.
+
+use std::{
+    cell::RefCell,
+    io::{Cursor, ErrorKind, Read, Write},
+    rc::Rc,
+    time::Duration,
+};
+
+use bf::ir::{self, BrainfuckNode};
+use bf::opt::PassManager;
+use bf::vm::{BoundsChecks, CellWidth, ConfigError, EofPolicy, Resumable, RuntimeError, TapePolicy, VMConfig, VMInterface, Yield, VM, VMBytecode, VMCranelift, LLVM};
+use inkwell::context::Context;
+
+/// An in-memory writer that can still be read after the VM took ownership of it.
+#[derive(Clone, Default)]
+struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// An in-memory writer that keeps every write it gets separately.
+#[derive(Clone, Default)]
+struct Writes(Rc<RefCell<Vec<Vec<u8>>>>);
+
+impl Writes {
+    fn bytes(&self) -> Vec<u8> {
+        self.0.borrow().concat()
+    }
+}
+
+impl Write for Writes {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.borrow_mut().push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Input that notes the output the program wrote so far every time it is read.
+struct Prompted {
+    input: Cursor<Vec<u8>>,
+    // the output of the backend being run is the last one
+    outputs: Rc<RefCell<Vec<Writes>>>,
+    seen: Rc<RefCell<Vec<Vec<u8>>>>,
+}
+
+impl Read for Prompted {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let output = self.outputs.borrow().last().map_or_else(Vec::new, Writes::bytes);
+        self.seen.borrow_mut().push(output);
+        self.input.read(buf)
+    }
+}
+
+/// Streams that fail on every call, writes like a closed pipe.
+struct Failing;
+
+impl Read for Failing {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(std::io::Error::other("input failed"))
+    }
+}
+
+impl Write for Failing {
+    fn write(&mut self, _buf: &[u8]) -> std::io::Result<usize> {
+        Err(ErrorKind::BrokenPipe.into())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Input with nothing to read yet, like an empty non-blocking socket.
+struct Blocked;
+
+impl Read for Blocked {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Err(ErrorKind::WouldBlock.into())
+    }
+}
+
+fn compile(src: &[u8], level: u8, config: &VMConfig) -> Vec<BrainfuckNode> {
+    let mut ir = ir::parse_bytes(src, false).expect("test program should parse");
+    PassManager::with_config(level, config).run(&mut ir);
+    ir
+}
+
+/// Run `ir` on both interpreters, which must agree on the output or the error.
+fn run_vm(ir: Vec<BrainfuckNode>, input: &[u8], config: &VMConfig) -> anyhow::Result<Vec<u8>> {
+    let result = run_tree(ir.clone(), input, config);
+    let bytecode = run_bytecode(ir, input, config);
+    match (&result, &bytecode) {
+        (Ok(tree), Ok(bytecode)) => assert_eq!(tree, bytecode, "bytecode output"),
+        (Err(tree), Err(bytecode)) => assert_eq!(format!("{:#}", tree), format!("{:#}", bytecode), "bytecode error"),
+        _ => panic!("tree walker returned {:?}, bytecode {:?}", result, bytecode),
+    }
+    result
+}
+
+fn run_tree(ir: Vec<BrainfuckNode>, input: &[u8], config: &VMConfig) -> anyhow::Result<Vec<u8>> {
+    let output = SharedOutput::default();
+    let input = Box::new(Cursor::new(input.to_vec()));
+    let mut vm = VM::with_config(ir, input, Box::new(output.clone()), config.clone())?;
+    vm.run()?;
+    let result = output.0.borrow().clone();
+    Ok(result)
+}
+
+fn run_bytecode(ir: Vec<BrainfuckNode>, input: &[u8], config: &VMConfig) -> anyhow::Result<Vec<u8>> {
+    let output = SharedOutput::default();
+    let input = Box::new(Cursor::new(input.to_vec()));
+    let mut vm = VMBytecode::with_config(ir, input, Box::new(output.clone()), config.clone())?;
+    vm.run()?;
+    let result = output.0.borrow().clone();
+    Ok(result)
+}
+
+fn run_cranelift(ir: Vec<BrainfuckNode>, input: &[u8], config: &VMConfig) -> anyhow::Result<Vec<u8>> {
+    let output = SharedOutput::default();
+    let input = Box::new(Cursor::new(input.to_vec()));
+    let mut vm = VMCranelift::with_config(ir, input, Box::new(output.clone()), config.clone())?;
+    vm.compile()?;
+    vm.run()?;
+    let result = output.0.borrow().clone();
+    Ok(result)
+}
+
+fn run_llvm(ir: Vec<BrainfuckNode>, input: &[u8], config: &VMConfig) -> anyhow::Result<Vec<u8>> {
+    let context = Context::create();
+    let output = SharedOutput::default();
+    let input = Box::new(Cursor::new(input.to_vec()));
+    let mut vm = LLVM::with_config(ir, input, Box::new(output.clone()), config.clone())?;
+    vm.compile(&context)?;
+    vm.run()?;
+    let result = output.0.borrow().clone();
+    Ok(result)
+}
+
+/// One of the `run_*` functions above.
+type RunFn = fn(Vec<BrainfuckNode>, &[u8], &VMConfig) -> anyhow::Result<Vec<u8>>;
+
+/// Run `src` at `level` on every backend with streams made by `input` and `output`, the results
+/// are in the order VM, VMBytecode, Cranelift, LLVM.
+fn run_streams(
+    src: &[u8],
+    level: u8,
+    input: impl Fn() -> Box<dyn Read>,
+    output: impl Fn() -> Box<dyn Write>,
+) -> Vec<anyhow::Result<Duration>> {
+    let config = VMConfig::default();
+    let ir = || compile(src, level, &config);
+    let mut results = Vec::new();
+    let mut vm = VM::new(ir(), input(), output()).unwrap();
+    results.push(vm.run());
+    let mut bytecode = VMBytecode::new(ir(), input(), output()).unwrap();
+    results.push(bytecode.run());
+    let mut cranelift = VMCranelift::new(ir(), input(), output()).unwrap();
+    cranelift.compile().unwrap();
+    results.push(cranelift.run());
+    let context = Context::create();
+    let mut llvm = LLVM::new(ir(), input(), output()).unwrap();
+    llvm.compile(&context).unwrap();
+    results.push(llvm.run());
+    results
+}
+
+/// Run `src` on every backend at every optimization level on a machine set up with `config`,
+/// check all outputs match `-O0` on `VM` and return that output.
+fn same_output(src: &[u8], input: &[u8], config: &VMConfig) -> Vec<u8> {
+    let expected = run_vm(compile(src, 0, config), input, config).unwrap();
+    for level in 0..=3 {
+        let ir = || compile(src, level, config);
+        assert_eq!(run_vm(ir(), input, config).unwrap(), expected, "VM -O{}", level);
+        assert_eq!(run_cranelift(ir(), input, config).unwrap(), expected, "Cranelift -O{}", level);
+        assert_eq!(run_llvm(ir(), input, config).unwrap(), expected, "LLVM -O{}", level);
+    }
+    expected
+}
+
+fn assert_same_output(src: &[u8], input: &[u8]) {
+    same_output(src, input, &VMConfig::default());
+}
+
+fn example(name: &str) -> Vec<u8> {
+    std::fs::read(format!("{}/example/{}", env!("CARGO_MANIFEST_DIR"), name)).unwrap()
+}
+
+#[test]
+fn examples() {
+    assert_same_output(&example("hello.bf"), b"");
+    assert_same_output(&example("hello_commented.bf"), b"");
+    assert_same_output(&example("squares.bf"), b"");
+}
+
+#[test]
+fn non_utf8_source() {
+    // a Latin-1 comment, `\xe9` on its own isn't valid UTF-8
+    let src = b"caf\xe9 \xa9 1997\n++++++++[>++++++++<-]>+.";
+    assert_eq!(same_output(src, b"", &VMConfig::default()), b"A");
+}
+
+#[test]
+fn deep_nesting() {
+    // the passes and every backend keep their own stack, a test thread couldn't recurse this deep
+    let nested = |depth: usize| format!("+{}-{}+.", "[".repeat(depth), "]".repeat(depth));
+    let config = VMConfig::default();
+    let src = nested(20_000);
+    for level in 0..=3 {
+        let output = run_vm(compile(src.as_bytes(), level, &config), b"", &config).unwrap();
+        assert_eq!(output, b"\x01", "-O{}", level);
+    }
+    // the JIT compilers take time superlinear in the depth
+    assert_eq!(same_output(nested(1_000).as_bytes(), b"", &config), b"\x01");
+}
+
+#[test]
+fn multiply_loops() {
+    // 7 * 6 copied to two cells with different factors, printed as raw bytes
+    assert_same_output(b"+++++++[>++++++<-]>[->+>+++<<]>.>.", b"");
+    // multiply to the left and wrap around 256
+    assert_same_output(b">>+++++[-<<++++++++++++++++++++++++++++++++++++++++++++++++++++++>>]<<.", b"");
+    // factors read from input, including zero
+    assert_same_output(b",[->++>+++<<]>.>.", b"\x05");
+    assert_same_output(b",[->++>+++<<]>.>.", b"\x00");
+}
+
+#[test]
+fn scan_loops() {
+    assert_same_output(b">>>+>+>+>+[<]>.[>]<.", b"");
+    // strided scans in both directions
+    assert_same_output(b">>+>>+>>+>>+[<<]>>.<<+[>>]<<.", b"");
+}
+
+#[test]
+fn deferred_moves() {
+    // cell accesses at offsets, with the pending move flushed before loops and at the end
+    assert_same_output(b">+>++>+++<<.>.>.<<<++++++[>>>++++++++<<<-]>>>.>,.<<.", b"x");
+    assert_same_output(b"+[>>,.<<-]>>>+<.>.", b"ab");
+}
+
+#[test]
+fn dead_code() {
+    let src = example("hello.bf");
+    let mut commented = b"[This prints hello world. It has +-<>., in it!]".to_vec();
+    commented.extend_from_slice(&src);
+    assert_same_output(&commented, b"");
+    assert_same_output(b",[.,][never runs]+-><.", b"abc\0");
+}
+
+#[test]
+fn partial_eval() {
+    // the prefix before `,` runs at compile time, the rest starts from its tape
+    assert_same_output(b"++++++++[>++++++++<-]>+.>>+++[<<+.>>-]<<<[-]>,[->+<]>.", b"\x03");
+}
+
+#[test]
+fn scan_off_the_tape() {
+    let is_overflow = |result: anyhow::Result<Vec<u8>>| {
+        matches!(result.unwrap_err().downcast_ref(), Some(RuntimeError::Overflow))
+    };
+    let config = VMConfig::default();
+    assert!(is_overflow(run_vm(compile(b"+[<]", 3, &config), b"", &config)));
+    assert!(is_overflow(run_cranelift(compile(b"+[<]", 3, &config), b"", &config)));
+    assert!(is_overflow(run_llvm(compile(b"+[<]", 3, &config), b"", &config)));
+}
+
+#[test]
+fn cell_widths() {
+    // prints `1` if the current cell isn't zero
+    let check = format!("[[-]{}.[-]]", "+".repeat(49));
+    let sixteen = "+".repeat(16);
+    let pow_256 = format!("{0}[>{0}<-]>", sixteen);
+    let pow_65536 = format!("{0}[>{1}<-]>[>{1}<-]>[>{1}<-]>", pow_256, sixteen);
+
+    for (cell_width, wraps_at_256, wraps_at_65536) in [
+        (CellWidth::U8, "", ""),
+        (CellWidth::U16, "1", ""),
+        (CellWidth::U32, "1", "1"),
+    ] {
+        let config = VMConfig { cell_width, ..VMConfig::default() };
+        let run = |src: String, input: &[u8]| same_output(src.as_bytes(), input, &config);
+
+        assert_eq!(run(format!("{}{}", pow_256, check), b""), wraps_at_256.as_bytes());
+        assert_eq!(run(format!("{}{}", pow_65536, check), b""), wraps_at_65536.as_bytes());
+        // output is the low byte of the cell, input is zero-extended
+        assert_eq!(run(format!("{}{}.", pow_256, "+".repeat(65)), b""), b"A");
+        assert_eq!(run(format!(",+{}", check), b"\xff"), wraps_at_256.as_bytes());
+        assert_eq!(run(">>>+>+>+>+[<]>.[>]<.".to_string(), b""), b"\x01\x01");
+    }
+}
+
+#[test]
+fn wrapping_tape() {
+    let config = VMConfig { tape_size: 8, tape_policy: TapePolicy::Wrap, ..VMConfig::default() };
+    let run = |src: &[u8], input: &[u8]| same_output(src, input, &config);
+
+    // count on the last cell, then step across the right end back to the first
+    assert_eq!(run(b"<++++++++[>++++++++<-]>+.", b""), b"A");
+    assert_eq!(run(b",[-<<+>>]<<.", b"\x05"), b"\x05");
+    // cell offsets that reach across either end
+    assert_eq!(run(b">,[->>>>>>>>>+<<<<<<<<<]>>>>>>>>>.", b"\x07"), b"\x07");
+    // scans continue at the other end
+    assert_eq!(run(b",>>>>>>>+[>]<.", b"\x03"), b"\x03");
+    assert_eq!(run(b",>+[<<]>.", b"\x04"), b"\x04");
+
+    // a scan over a tape without zero cells never ends
+    let is_endless = |result: anyhow::Result<Vec<u8>>| {
+        matches!(result.unwrap_err().downcast_ref(), Some(RuntimeError::EndlessScan))
+    };
+    let src = b",>+>+>+>+>+>+>+[>]";
+    assert!(is_endless(run_vm(compile(src, 3, &config), b"\x01", &config)));
+    assert!(is_endless(run_cranelift(compile(src, 3, &config), b"\x01", &config)));
+    assert!(is_endless(run_llvm(compile(src, 3, &config), b"\x01", &config)));
+}
+
+#[test]
+fn growing_tape() {
+    let config = VMConfig { tape_size: 4, tape_policy: TapePolicy::Grow, ..VMConfig::default() };
+    for level in 0..=3 {
+        let run = |src: &[u8], input: &[u8]| run_vm(compile(src, level, &config), input, &config).unwrap();
+        assert_eq!(run(b",>>>>>>>>>+++[>]<.", b"\x01"), b"\x03");
+        // scans stop at the first cell past the end
+        assert_eq!(run(b",>+>+>+[>]+.", b"\x01"), b"\x01");
+        assert_eq!(run(b">>,[>>>>>>>>>>+<<<<<<<<<<-]>>>>>>>>>>.", b"\x09"), b"\x09");
+    }
+    // the tape only grows to the right
+    assert!(run_vm(compile(b"<+", 0, &config), b"", &config).is_err());
+
+    // only the interpreter can grow the tape
+    let is_unsupported = |result: anyhow::Result<Vec<u8>>| {
+        matches!(result.unwrap_err().downcast_ref(), Some(ConfigError::UnsupportedPolicy { .. }))
+    };
+    assert!(is_unsupported(run_cranelift(compile(b"+.", 0, &config), b"", &config)));
+    assert!(is_unsupported(run_llvm(compile(b"+.", 0, &config), b"", &config)));
+}
+
+#[test]
+fn fixed_tape() {
+    let config = VMConfig { tape_size: 4, ..VMConfig::default() };
+    for level in 0..=3 {
+        let run = |src: &[u8]| run_vm(compile(src, level, &config), b"\x01", &config);
+        assert_eq!(run(b",>>>+.").unwrap(), b"\x01");
+        assert!(matches!(run(b",>>>>+.").unwrap_err().downcast_ref(), Some(RuntimeError::Overflow)));
+    }
+}
+
+#[test]
+fn bidirectional_tape() {
+    let config = VMConfig { tape_size: 16, bidirectional: true, ..VMConfig::default() };
+    let run = |src: &[u8], input: &[u8]| same_output(src, input, &config);
+
+    assert_eq!(run(b"<<+++++[->>+++++++++++++<<]>>.", b""), b"A");
+    assert_eq!(run(b"<<<+++>,<.>.", b"x"), b"\x03x");
+    assert_eq!(run(b"<,[->>>+<<<]>>>.", b"\x05"), b"\x05");
+    assert_eq!(run(b",<+<+<+[<]>>>>.", b"\x07"), b"\x07");
+
+    // a growing tape grows to the left as well
+    let config = VMConfig { tape_size: 4, tape_policy: TapePolicy::Grow, bidirectional: true, ..VMConfig::default() };
+    for level in 0..=3 {
+        let run = |src: &[u8], input: &[u8]| run_vm(compile(src, level, &config), input, &config).unwrap();
+        assert_eq!(run(b"<<<<<<<<<<,[<<<<<<+>>>>>>-]<<<<<<.", b"\x09"), b"\x09");
+        assert_eq!(run(b",<+<+<+<+<+[<]+>>>>>>.<<<<<<.", b"\x02"), b"\x02\x01");
+        assert_eq!(run(b"<<<<<<+++>>>>>>,<<<<<<.", b"x"), b"\x03");
+    }
+}
+
+#[test]
+fn end_of_input() {
+    let echo = example("echo.bf");
+    for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+        let config = |eof| VMConfig { cell_width, eof, ..VMConfig::default() };
+
+        // `echo.bf` stops on a zero cell, which it gets at the end of input unless -1 is stored
+        for eof in [EofPolicy::Zero, EofPolicy::Unchanged] {
+            assert_eq!(same_output(&echo, b"echo me", &config(eof)), b"echo me");
+            assert_eq!(same_output(&echo, b"", &config(eof)), b"");
+        }
+        // the usual echo for -1 at the end of input
+        assert_eq!(same_output(b",+[-.,+]", b"echo me", &config(EofPolicy::MinusOne)), b"echo me");
+
+        // what the cell holds after `,` at the end of input, printed as its low byte
+        assert_eq!(same_output(b"+++,.", b"", &config(EofPolicy::Zero)), b"\x00");
+        assert_eq!(same_output(b"+++,.", b"", &config(EofPolicy::MinusOne)), b"\xff");
+        assert_eq!(same_output(b"+++,.", b"", &config(EofPolicy::Unchanged)), b"\x03");
+        // -1 fills the whole cell, not just the low byte
+        assert_eq!(same_output(b",+[+++++++++++++++++++++++++++++++++++++++++++++++++.[-]]", b"", &config(EofPolicy::MinusOne)), b"");
+    }
+}
+
+#[test]
+fn pointer_off_the_tape() {
+    let is_overflow = |result: anyhow::Result<Vec<u8>>| {
+        matches!(result.unwrap_err().downcast_ref(), Some(RuntimeError::Overflow))
+    };
+    let config = VMConfig { tape_size: 16, ..VMConfig::default() };
+    for src in [&b"<+"[..], b">>>>>>>>>>>>>>>>+", b"+[>+]", b",[<<<<<+>>>>>-]", b">>,[>>>>>>>>>>>>>>+<<<<<<<<<<<<<<-]"] {
+        for level in 0..=3 {
+            let ir = || compile(src, level, &config);
+            assert!(is_overflow(run_vm(ir(), b"\x01", &config)), "VM -O{}", level);
+            assert!(is_overflow(run_cranelift(ir(), b"\x01", &config)), "Cranelift -O{}", level);
+            assert!(is_overflow(run_llvm(ir(), b"\x01", &config)), "LLVM -O{}", level);
+        }
+    }
+
+    // a multiply loop that doesn't run never touches the cells it would add to
+    assert_eq!(same_output(b",[-<+>]+.", b"\x00", &config), b"\x01");
+
+    // programs that stay on the tape run the same without checks
+    let config = VMConfig { bounds_checks: BoundsChecks::Off, ..VMConfig::default() };
+    assert_eq!(same_output(&example("hello.bf"), b"", &config), b"Hello World!\n");
+}
+
+#[test]
+fn error_positions() {
+    // the runs merged from -O1 on don't move the position of the `<` that leaves the tape
+    let config = VMConfig::default();
+    for level in 0..=3 {
+        let err = run_vm(compile(b"++\n  +<", level, &config), b"", &config).unwrap_err();
+        assert_eq!(format!("{:#}", err), "at 2:4: overflow", "-O{}", level);
+    }
+}
+
+#[test]
+fn io_errors() {
+    let kind = |result: anyhow::Result<Duration>| result.unwrap_err().downcast::<std::io::Error>().unwrap().kind();
+    for level in 0..=3 {
+        // single bytes, and the output of `hello.bf` that -O3 writes at once
+        for src in [&b"+."[..], &example("hello.bf")] {
+            for result in run_streams(src, level, || Box::new(std::io::empty()), || Box::new(Failing)) {
+                assert_eq!(kind(result), ErrorKind::BrokenPipe, "-O{}", level);
+            }
+        }
+
+        // the program stops at the failed read
+        let output = SharedOutput::default();
+        for result in run_streams(b",+.", level, || Box::new(Failing), || Box::new(output.clone())) {
+            assert_eq!(kind(result), ErrorKind::Other, "-O{}", level);
+        }
+        assert!(output.0.borrow().is_empty());
+    }
+}
+
+#[test]
+fn output_flushing() {
+    let squares = example("squares.bf");
+    let expected = same_output(&squares, b"", &VMConfig::default());
+    for level in 0..=3 {
+        let outputs = Rc::new(RefCell::new(Vec::new()));
+        let output = || -> Box<dyn Write> {
+            let writes = Writes::default();
+            outputs.borrow_mut().push(writes.clone());
+            Box::new(writes)
+        };
+
+        // output is buffered, and all of it arrives by the end of the run
+        for result in run_streams(&squares, level, || Box::new(std::io::empty()), output) {
+            result.unwrap();
+        }
+        for writes in outputs.take() {
+            assert_eq!(writes.bytes(), expected, "-O{}", level);
+            assert!(writes.0.borrow().len() < expected.len() / 100, "-O{}", level);
+        }
+
+        // a prompt is out before the program waits for input
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let input = || -> Box<dyn Read> {
+            Box::new(Prompted { input: Cursor::new(b"x".to_vec()), outputs: outputs.clone(), seen: seen.clone() })
+        };
+        let results = run_streams(b">++++++++[<++++++++>-]<+.,.", level, input, output);
+        let backends = results.len();
+        for result in results {
+            result.unwrap();
+        }
+        for writes in outputs.take() {
+            assert_eq!(writes.bytes(), b"Ax", "-O{}", level);
+        }
+        assert_eq!(*seen.borrow(), vec![b"A"; backends], "-O{}", level);
+
+        // and so is the output before an error
+        for result in run_streams(b"+.<", level, || Box::new(std::io::empty()), output) {
+            assert!(matches!(result.unwrap_err().downcast_ref(), Some(RuntimeError::Overflow)));
+        }
+        for writes in outputs.take() {
+            assert_eq!(writes.bytes(), b"\x01", "-O{}", level);
+        }
+    }
+}
+
+#[test]
+fn fuel() {
+    let steps = |result: anyhow::Result<Vec<u8>>| match result.unwrap_err().downcast_ref() {
+        Some(RuntimeError::FuelExhausted { steps }) => *steps,
+        _ => panic!("expected the fuel to run out"),
+    };
+
+    // a program that never terminates stops once the fuel is gone
+    let config = VMConfig { fuel: Some(1000), ..VMConfig::default() };
+    for level in 0..=3 {
+        let ir = || compile(b"+[>+<]", level, &config);
+        assert_eq!(steps(run_vm(ir(), b"", &config)), 1000, "VM -O{}", level);
+        assert_eq!(steps(run_cranelift(ir(), b"", &config)), 1000, "Cranelift -O{}", level);
+        assert_eq!(steps(run_llvm(ir(), b"", &config)), 1000, "LLVM -O{}", level);
+    }
+
+    // a program that terminates within its budget runs as usual
+    let config = VMConfig { fuel: Some(1_000_000), ..VMConfig::default() };
+    assert_eq!(same_output(&example("hello.bf"), b"", &config), b"Hello World!\n");
+
+    // nothing runs at compile time with a limit, so the 8 iterations of a loop that -O2 would
+    // evaluate run out of fuel at every level
+    let config = VMConfig { fuel: Some(5), ..VMConfig::default() };
+    for level in 0..=3 {
+        let ir = || compile(b"++++++++[>+++++.<-]", level, &config);
+        assert_eq!(steps(run_vm(ir(), b"", &config)), 5, "VM -O{}", level);
+        assert_eq!(steps(run_cranelift(ir(), b"", &config)), 5, "Cranelift -O{}", level);
+        assert_eq!(steps(run_llvm(ir(), b"", &config)), 5, "LLVM -O{}", level);
+    }
+
+    // `+++[-]` takes 8 steps in the interpreters, the `+++` is one instruction and the loop
+    // condition is checked 4 times, and 3 loop iterations in JIT code
+    let run = |fuel, run: RunFn| {
+        let config = VMConfig { fuel: Some(fuel), ..VMConfig::default() };
+        run(compile(b"+++[-]", 0, &config), b"", &config)
+    };
+    assert!(run(8, run_vm).is_ok());
+    assert_eq!(steps(run(7, run_vm)), 7);
+    assert!(run(3, run_cranelift).is_ok());
+    assert_eq!(steps(run(2, run_cranelift)), 2);
+    assert!(run(3, run_llvm).is_ok());
+    assert_eq!(steps(run(2, run_llvm)), 2);
+}
+
+#[test]
+fn cancellation() {
+    let is_cancelled = |result: anyhow::Result<Duration>| {
+        matches!(result.unwrap_err().downcast_ref(), Some(RuntimeError::Cancelled))
+    };
+    // cancel a program that never terminates from another thread while it runs, or before
+    let cancel_after = |vm: &dyn VMInterface, delay: Option<Duration>| {
+        let cancel = vm.cancel_handle();
+        match delay {
+            Some(delay) => {
+                std::thread::spawn(move || {
+                    std::thread::sleep(delay);
+                    cancel.cancel();
+                });
+            }
+            None => cancel.cancel(),
+        }
+    };
+    let output = || Box::new(std::io::sink());
+    for delay in [None, Some(Duration::from_millis(50))] {
+        for level in 0..=3 {
+            let ir = || compile(b"+[>+<]", level, &VMConfig::default());
+            let mut vm = VM::new(ir(), Box::new(std::io::empty()), output()).unwrap();
+            cancel_after(&vm, delay);
+            assert!(is_cancelled(vm.run()), "VM -O{}", level);
+            let mut bytecode = VMBytecode::new(ir(), Box::new(std::io::empty()), output()).unwrap();
+            cancel_after(&bytecode, delay);
+            assert!(is_cancelled(bytecode.run()), "VMBytecode -O{}", level);
+            let mut cranelift = VMCranelift::new(ir(), Box::new(std::io::empty()), output()).unwrap();
+            cranelift.compile().unwrap();
+            cancel_after(&cranelift, delay);
+            assert!(is_cancelled(cranelift.run()), "Cranelift -O{}", level);
+            let context = Context::create();
+            let mut llvm = LLVM::new(ir(), Box::new(std::io::empty()), output()).unwrap();
+            llvm.compile(&context).unwrap();
+            cancel_after(&llvm, delay);
+            assert!(is_cancelled(llvm.run()), "LLVM -O{}", level);
+        }
+    }
+
+    // a program without loops has nowhere to stop, the handle only stops loops
+    let mut vm = VM::new(compile(b"+.", 0, &VMConfig::default()), Box::new(std::io::empty()), output()).unwrap();
+    vm.cancel_handle().cancel();
+    assert!(vm.run().is_ok());
+}
+
+/// Resume `vm` until the program ends, feeding the next of `chunks` whenever it needs input
+/// and closing the input once they are gone, and return everything it yielded.
+fn transcript<V: VMInterface>(vm: &mut Resumable<V>, chunks: &[&[u8]]) -> Vec<Yield> {
+    let mut chunks = chunks.iter();
+    let mut yields = Vec::new();
+    loop {
+        let yielded = vm.resume().unwrap();
+        match &yielded {
+            Yield::NeedInput => match chunks.next() {
+                Some(chunk) => vm.feed(chunk),
+                None => vm.close_input(),
+            },
+            Yield::Output(_) => {}
+            Yield::Done => {
+                yields.push(yielded);
+                return yields;
+            }
+        }
+        yields.push(yielded);
+    }
+}
+
+/// A program, the input fed to it in chunks and everything resuming it yields.
+type Session<'a> = (&'a [u8], &'a [&'a [u8]], Vec<Yield>);
+
+#[test]
+fn resumable() {
+    let output = |bytes: &[u8]| Yield::Output(bytes.to_vec());
+    let cases: [Session; 2] = [
+        // reverse the input, the `,` is resumed inside a loop with the pointer moved
+        (
+            b">,[>,]<[.<]",
+            &[b"ab", b"c"],
+            vec![Yield::NeedInput, Yield::NeedInput, Yield::NeedInput, output(b"cba"), Yield::Done],
+        ),
+        // a prompt is returned before the program waits for the answer
+        (
+            b"++++++++[>++++++++<-]>+.,.",
+            &[b"z"],
+            vec![output(b"A"), Yield::NeedInput, output(b"z"), Yield::Done],
+        ),
+    ];
+    for (src, chunks, expected) in &cases {
+        // suspended JIT code keeps its fuel
+        for fuel in [None, Some(1000)] {
+            let config = VMConfig { fuel, ..VMConfig::default() };
+            for level in 0..=3 {
+                let ir = || compile(src, level, &config);
+                let mut vm = Resumable::<VM>::new(ir(), config.clone()).unwrap();
+                assert_eq!(&transcript(&mut vm, chunks), expected, "VM -O{}", level);
+                let mut bytecode = Resumable::<VMBytecode>::new(ir(), config.clone()).unwrap();
+                assert_eq!(&transcript(&mut bytecode, chunks), expected, "VMBytecode -O{}", level);
+                let mut cranelift = Resumable::<VMCranelift>::new(ir(), config.clone()).unwrap();
+                cranelift.vm().compile().unwrap();
+                assert_eq!(&transcript(&mut cranelift, chunks), expected, "Cranelift -O{}", level);
+                let context = Context::create();
+                let mut llvm = Resumable::<LLVM>::new(ir(), config.clone()).unwrap();
+                llvm.vm().compile(&context).unwrap();
+                assert_eq!(&transcript(&mut llvm, chunks), expected, "LLVM -O{}", level);
+            }
+        }
+    }
+
+    // a JIT has to be compiled first
+    let config = VMConfig::default();
+    let mut cranelift = Resumable::<VMCranelift>::new(compile(b".", 0, &config), config.clone()).unwrap();
+    assert!(cranelift.resume().is_err());
+    assert!(cranelift.vm().run().is_err());
+    let mut llvm = Resumable::<LLVM>::new(compile(b".", 0, &config), config.clone()).unwrap();
+    assert!(llvm.resume().is_err());
+
+    // a `,` that would block is an error for `run`
+    let kind = |result: anyhow::Result<Duration>| result.unwrap_err().downcast::<std::io::Error>().unwrap().kind();
+    for level in 0..=3 {
+        for result in run_streams(b",+.", level, || Box::new(Blocked), || Box::new(std::io::sink())) {
+            assert_eq!(kind(result), ErrorKind::WouldBlock, "-O{}", level);
+        }
+    }
+}
+
+/// Resume `vm` `n` times without input and return what it yielded.
+fn resume_times<V: VMInterface>(vm: &mut Resumable<V>, n: usize) -> Vec<Yield> {
+    (0..n).map(|_| vm.resume().unwrap()).collect()
+}
+
+#[test]
+fn bounded_output() {
+    let config = VMConfig { output_chunk: Some(16), ..VMConfig::default() };
+    let output = |bytes: &[u8]| Yield::Output(bytes.to_vec());
+
+    // a program that never stops writing still returns
+    let endless = vec![output(&[1; 16]); 3];
+    // a program stopped at a `.` goes on after it, in a loop and with a read after it. It
+    // counts down from the `2` it reads, so it isn't run at compile time.
+    let countdown: Vec<u8> = (1..=b'2').rev().collect();
+    let mut counted = vec![Yield::NeedInput];
+    counted.extend(countdown.chunks(16).map(output));
+    counted.extend([Yield::NeedInput, output(b"x"), Yield::Done]);
+    for level in 0..=3 {
+        let endless_ir = || compile(b"+[.]", level, &config);
+        let countdown_ir = || compile(b",[.-],.", level, &config);
+
+        let mut vm = Resumable::<VM>::new(endless_ir(), config.clone()).unwrap();
+        assert_eq!(resume_times(&mut vm, 3), endless, "VM -O{}", level);
+        let mut vm = Resumable::<VM>::new(countdown_ir(), config.clone()).unwrap();
+        assert_eq!(transcript(&mut vm, &[b"2", b"x"]), counted, "VM -O{}", level);
+
+        let mut bytecode = Resumable::<VMBytecode>::new(endless_ir(), config.clone()).unwrap();
+        assert_eq!(resume_times(&mut bytecode, 3), endless, "VMBytecode -O{}", level);
+        let mut bytecode = Resumable::<VMBytecode>::new(countdown_ir(), config.clone()).unwrap();
+        assert_eq!(transcript(&mut bytecode, &[b"2", b"x"]), counted, "VMBytecode -O{}", level);
+
+        let mut cranelift = Resumable::<VMCranelift>::new(endless_ir(), config.clone()).unwrap();
+        cranelift.vm().compile().unwrap();
+        assert_eq!(resume_times(&mut cranelift, 3), endless, "Cranelift -O{}", level);
+        let mut cranelift = Resumable::<VMCranelift>::new(countdown_ir(), config.clone()).unwrap();
+        cranelift.vm().compile().unwrap();
+        assert_eq!(transcript(&mut cranelift, &[b"2", b"x"]), counted, "Cranelift -O{}", level);
+
+        let context = Context::create();
+        let mut llvm = Resumable::<LLVM>::new(endless_ir(), config.clone()).unwrap();
+        llvm.vm().compile(&context).unwrap();
+        assert_eq!(resume_times(&mut llvm, 3), endless, "LLVM -O{}", level);
+        let mut llvm = Resumable::<LLVM>::new(countdown_ir(), config.clone()).unwrap();
+        llvm.vm().compile(&context).unwrap();
+        assert_eq!(transcript(&mut llvm, &[b"2", b"x"]), counted, "LLVM -O{}", level);
+    }
+
+    // `run` writes everything
+    let ir = compile(b",[.-]", 3, &config);
+    assert_eq!(run_vm(ir, b"2", &config).unwrap(), countdown);
+}
+
+/// Run an echo program on one end of an in-memory duplex stream, the other end checks every
+/// line comes back before it sends the next.
+async fn echo_session<V: VMInterface>(mut vm: Resumable<V>) {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let (client, server) = tokio::io::duplex(64);
+    let (server_input, server_output) = tokio::io::split(server);
+    let (mut client_input, mut client_output) = tokio::io::split(client);
+    let client = async move {
+        let mut line = [0u8; 5];
+        for sent in [b"ping\n", b"pong\n"] {
+            client_output.write_all(sent).await.unwrap();
+            client_input.read_exact(&mut line).await.unwrap();
+            assert_eq!(&line, sent);
+        }
+        // the program ends with the input
+        client_output.shutdown().await.unwrap();
+        let mut rest = Vec::new();
+        client_input.read_to_end(&mut rest).await.unwrap();
+        assert!(rest.is_empty());
+    };
+    let (result, ()) = tokio::join!(vm.run_async(server_input, server_output), client);
+    result.unwrap();
+}
+
+#[tokio::test]
+async fn async_streams() {
+    use tokio::io::AsyncReadExt;
+
+    let config = VMConfig::default();
+    for level in 0..=3 {
+        let ir = || compile(b",[.,]", level, &config);
+        echo_session(Resumable::<VM>::new(ir(), config.clone()).unwrap()).await;
+        echo_session(Resumable::<VMBytecode>::new(ir(), config.clone()).unwrap()).await;
+        let mut cranelift = Resumable::<VMCranelift>::new(ir(), config.clone()).unwrap();
+        cranelift.vm().compile().unwrap();
+        echo_session(cranelift).await;
+        let context = Context::create();
+        let mut llvm = Resumable::<LLVM>::new(ir(), config.clone()).unwrap();
+        llvm.vm().compile(&context).unwrap();
+        echo_session(llvm).await;
+    }
+
+    // the future isn't `Send`, a server spawns a session on a `LocalSet`
+    let sessions = tokio::task::LocalSet::new();
+    sessions.run_until(async {
+        let vm = Resumable::<VM>::new(compile(b",[.,]", 3, &config), config.clone()).unwrap();
+        tokio::task::spawn_local(echo_session(vm)).await.unwrap();
+    }).await;
+
+    // the output written before an error still arrives
+    let (client, server) = tokio::io::duplex(64);
+    let (server_input, server_output) = tokio::io::split(server);
+    let (mut client_input, _client_output) = tokio::io::split(client);
+    let mut vm = Resumable::<VM>::new(compile(b"+++++++[>++++++++++<-]>+++++.<<", 0, &config), config.clone()).unwrap();
+    let err = vm.run_async(server_input, server_output).await.unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(RuntimeError::Overflow)));
+    let mut output = [0u8; 1];
+    client_input.read_exact(&mut output).await.unwrap();
+    assert_eq!(&output, b"K");
+}
+
+/// Run a program that writes forever before it reads, the other end of the stream
+/// reads a lot of its output before the future is dropped.
+async fn endless_output<V: VMInterface>(mut vm: Resumable<V>) {
+    use tokio::io::AsyncReadExt;
+
+    let (client, server) = tokio::io::duplex(64);
+    let (server_input, server_output) = tokio::io::split(server);
+    let (mut client_input, _client_output) = tokio::io::split(client);
+    let mut output = vec![0u8; 1024 * 1024];
+    tokio::select! {
+        result = vm.run_async(server_input, server_output) => panic!("the program ended with {:?}", result),
+        read = client_input.read_exact(&mut output) => read.unwrap(),
+    };
+    assert!(output.iter().all(|&byte| byte == 1));
+}
+
+#[tokio::test]
+async fn async_output() {
+    let config = VMConfig::default();
+    for level in 0..=3 {
+        let ir = || compile(b"+[.],", level, &config);
+        endless_output(Resumable::<VM>::new(ir(), config.clone()).unwrap()).await;
+        endless_output(Resumable::<VMBytecode>::new(ir(), config.clone()).unwrap()).await;
+        let mut cranelift = Resumable::<VMCranelift>::new(ir(), config.clone()).unwrap();
+        cranelift.vm().compile().unwrap();
+        endless_output(cranelift).await;
+        let context = Context::create();
+        let mut llvm = Resumable::<LLVM>::new(ir(), config.clone()).unwrap();
+        llvm.vm().compile(&context).unwrap();
+        endless_output(llvm).await;
+    }
+}
+
+#[test]
+#[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+fn guard_pages() {
+    let is_overflow = |result: anyhow::Result<Vec<u8>>| {
+        matches!(result.unwrap_err().downcast_ref(), Some(RuntimeError::Overflow))
+    };
+    let far = |n| format!(">{}+", ">".repeat(n)).into_bytes();
+    let loop_step = |n| format!("+[{}+]", ">".repeat(n)).into_bytes();
+    for cell_width in [CellWidth::U8, CellWidth::U16, CellWidth::U32] {
+        // 4096 cells fill whole pages at every width
+        for bidirectional in [false, true] {
+            let config = VMConfig {
+                cell_width,
+                tape_size: 4096,
+                bidirectional,
+                bounds_checks: BoundsChecks::GuardPages,
+                ..VMConfig::default()
+            };
+            let before = if bidirectional { b"<".repeat(4096) } else { Vec::new() };
+            let programs = [
+                [&before[..], b"<+"].concat(),
+                far(8191),
+                far(20000),
+                loop_step(1),
+                loop_step(3000),
+                [&before[..], b"+[<+]"].concat(),
+                [&before[..], b",[<<<<<+>>>>>-]"].concat(),
+            ];
+            for src in &programs {
+                for level in 0..=3 {
+                    let ir = || compile(src, level, &config);
+                    assert!(is_overflow(run_vm(ir(), b"\x01", &config)), "VM -O{}", level);
+                    assert!(is_overflow(run_cranelift(ir(), b"\x01", &config)), "Cranelift -O{}", level);
+                    assert!(is_overflow(run_llvm(ir(), b"\x01", &config)), "LLVM -O{}", level);
+                }
+            }
+
+            // programs that stay on the tape run the same, and so does a multiply loop that
+            // would add to cells off the tape but never runs
+            assert_eq!(same_output(&example("hello.bf"), b"", &config), b"Hello World!\n");
+            assert_eq!(same_output(&[&before[..], b",[-<+>]+."].concat(), b"\x00", &config), b"\x01");
+        }
+    }
+
+    // the ends of the tape have to be page boundaries
+    let config = VMConfig { tape_size: 100, bounds_checks: BoundsChecks::GuardPages, ..VMConfig::default() };
+    let err = run_cranelift(compile(b"+", 0, &config), b"", &config).unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(ConfigError::UnalignedTape { .. })));
+    let err = run_llvm(compile(b"+", 0, &config), b"", &config).unwrap_err();
+    assert!(matches!(err.downcast_ref(), Some(ConfigError::UnalignedTape { .. })));
+}
@@ -0,0 +1,61 @@
+//! Checks on the `bf` binary.
+
+use std::process::Command;
+
+#[test]
+fn bracket_mismatches() {
+    let output = Command::new(env!("CARGO_BIN_EXE_bf"))
+        .arg(concat!(env!("CARGO_MANIFEST_DIR"), "/example/error.bf"))
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(3));
+
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let lines: Vec<&str> = stderr.lines().collect();
+    assert_eq!(lines[0], "error: unmatched `[` opened at line 1, column 11");
+    assert!(lines[1].ends_with("example/error.bf:1:11"), "{}", lines[1]);
+    assert_eq!(lines[4], format!("  | {}^", " ".repeat(10)));
+
+    // a usage error has a different status
+    let output = Command::new(env!("CARGO_BIN_EXE_bf"))
+        .args(["--no-such-flag", concat!(env!("CARGO_MANIFEST_DIR"), "/example/error.bf")])
+        .output()
+        .unwrap();
+    assert_eq!(output.status.code(), Some(2));
+}
+
+#[test]
+fn non_utf8_source() {
+    let path = std::env::temp_dir().join(format!("bf-latin1-{}.bf", std::process::id()));
+    std::fs::write(&path, b"caf\xe9 \xa9 1997\n++++++++[>++++++++<-]>+.").unwrap();
+    let output = Command::new(env!("CARGO_BIN_EXE_bf")).arg(&path).output().unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(stdout.starts_with("Running program without JIT:\nA"), "{}", stdout);
+}
+
+#[test]
+fn print_ir_after_all() {
+    let output = Command::new(env!("CARGO_BIN_EXE_bf"))
+        .args(["-O2", "--print-ir-after-all", concat!(env!("CARGO_MANIFEST_DIR"), "/example/echo.bf")])
+        .output()
+        .unwrap();
+    assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stderr));
+
+    // a header for every pass of the pipeline, each followed by the IR
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let headers: Vec<&str> = stderr.lines().filter(|line| line.starts_with("; ***")).collect();
+    let passes = ["combine-runs", "clear-loops", "dead-code", "mul-loops", "clear-loops", "scan-loops", "partial-eval"];
+    let expected: Vec<String> = passes.iter().map(|pass| format!("; *** IR after {} ***", pass)).collect();
+    assert_eq!(headers, expected);
+    assert!(stderr.lines().count() > headers.len(), "{}", stderr);
+
+    // and nothing without the flag
+    let output = Command::new(env!("CARGO_BIN_EXE_bf"))
+        .args(["-O2", concat!(env!("CARGO_MANIFEST_DIR"), "/example/echo.bf")])
+        .output()
+        .unwrap();
+    assert!(output.stderr.is_empty(), "{}", String::from_utf8_lossy(&output.stderr));
+}
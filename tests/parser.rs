@@ -0,0 +1,92 @@
+//! Checks on what the parser accepts and the IR it produces.
+
+use bf::ir::{self, BrainfuckIR, BrainfuckNode, ParseError};
+
+/// The top-level instructions of a block as printed in IR dumps, loop bodies are left out.
+fn instructions(block: &[BrainfuckNode]) -> Vec<String> {
+    block.iter().map(|node| node.ir.to_string()).collect()
+}
+
+#[test]
+fn comments_are_ignored() {
+    let src = "#!/usr/bin/env bf\nAdd two: ++ then print it: .\n[loop: -]";
+    let ir = ir::parse(src, false).unwrap();
+    assert_eq!(instructions(&ir), ["add [+0] 2", "put [+0]", "loop"]);
+    match &ir[2].ir {
+        BrainfuckIR::Loop(body) => assert_eq!(instructions(body), ["sub [+0] 1"]),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn strict_rejects_comments() {
+    // whitespace is still allowed
+    let ir = ir::parse(" ++\n\t. ", true).unwrap();
+    assert_eq!(instructions(&ir), ["add [+0] 2", "put [+0]"]);
+
+    let err = ir::parse("++\n\t.# print", true).unwrap_err();
+    match err {
+        ParseError::Syntax { position, found } => {
+            assert_eq!((position.offset, position.line, position.column, found), (5, 2, 3, b'#'));
+        }
+        _ => panic!("expected a syntax error, got {:?}", err),
+    }
+    assert_eq!(
+        err.to_string(),
+        "syntax error at 2:3: unexpected `#`, only whitespace is allowed between instructions in strict mode"
+    );
+}
+
+#[test]
+fn deep_nesting() {
+    // far deeper than the stack of a test thread would allow if anything recursed per level
+    let depth = 1_000_000;
+    let src = format!("+{}-{}", "[".repeat(depth), "]".repeat(depth));
+    let ir = ir::parse(&src, false).unwrap();
+    let mut block = &ir[1..];
+    for _ in 0..depth {
+        let [BrainfuckNode { ir: BrainfuckIR::Loop(body), .. }] = block else {
+            panic!("expected a single loop");
+        };
+        block = body;
+    }
+    assert_eq!(instructions(block), ["sub [+0] 1"]);
+
+    // an unclosed `[` is still a bracket error
+    let unclosed = "[".repeat(depth);
+    assert!(matches!(ir::parse(&unclosed, false), Err(ParseError::Brackets(_))));
+}
+
+/// Parse with both parsers, which must agree on the IR or the error.
+fn assert_same_as_peg(src: &[u8]) {
+    for strict in [false, true] {
+        let hand = ir::parse_bytes(src, strict);
+        let peg = ir::grammar::parse_peg(src, strict);
+        let src = src.escape_ascii();
+        assert_eq!(format!("{:?}", hand), format!("{:?}", peg), "\"{}\" strict {}", src, strict);
+    }
+}
+
+#[test]
+fn same_as_peg() {
+    let examples = concat!(env!("CARGO_MANIFEST_DIR"), "/example");
+    for entry in std::fs::read_dir(examples).unwrap() {
+        assert_same_as_peg(&std::fs::read(entry.unwrap().path()).unwrap());
+    }
+
+    // random sources from commands, whitespace, comments and a non-UTF-8 byte
+    const ALPHABET: &[u8] = b"+++---<<>>..,,[[[]]] \n\t#\xe9";
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut next = |n: usize| {
+        // xorshift, enough to cover the grammar
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        state as usize % n
+    };
+    for _ in 0..10_000 {
+        let len = next(40);
+        let src: Vec<u8> = (0..len).map(|_| ALPHABET[next(ALPHABET.len())]).collect();
+        assert_same_as_peg(&src);
+    }
+}
@@ -0,0 +1,125 @@
+//! Checks on the IR the optimization passes produce.
+
+use bf::ir::{self, BrainfuckNode};
+use bf::opt::{ClearLoops, CombineRuns, DeadCode, Pass, PartialEval, PassManager};
+use bf::vm::{TapePolicy, VMConfig};
+
+fn parse(src: &str) -> Vec<BrainfuckNode> {
+    ir::parse(src, false).expect("test program should parse")
+}
+
+/// The top-level instructions of a block as printed in IR dumps, loop bodies are left out.
+fn instructions(block: &[BrainfuckNode]) -> Vec<String> {
+    block.iter().map(|node| node.ir.to_string()).collect()
+}
+
+#[test]
+fn combine_runs_merges_spans() {
+    let mut ir = parse("+ +\n+>");
+    CombineRuns.run(&mut ir);
+    assert_eq!(instructions(&ir), ["add [+0] 3", "right 1"]);
+
+    // the merged run covers all three `+`, the `>` keeps its own span
+    let spans: Vec<String> = ir.iter().map(|node| node.span.to_string()).collect();
+    assert_eq!(spans, ["1:1-2:2", "2:2-2:3"]);
+    assert_eq!((ir[0].span.start.offset, ir[0].span.end.offset), (0, 5));
+}
+
+#[test]
+fn clear_loops() {
+    let clear = |src| {
+        let mut ir = parse(src);
+        ClearLoops.run(&mut ir);
+        instructions(&ir)
+    };
+    // an odd step reaches zero from any value
+    for src in [",[-].", ",[+].", ",[---]."] {
+        assert_eq!(clear(src), ["get [+0]", "set [+0] 0", "put [+0]"], "{}", src);
+    }
+    // an even step never does on odd values of 8-bit cells
+    assert_eq!(clear(",[--]"), ["get [+0]", "loop"]);
+    // a following run of the same cell is part of the set
+    assert_eq!(clear(",[-]+++."), ["get [+0]", "set [+0] 3", "put [+0]"]);
+    assert_eq!(clear(",[+]-."), ["get [+0]", "set [+0] 4294967295", "put [+0]"]);
+    assert_eq!(clear(",[-]>+"), ["get [+0]", "set [+0] 0", "right 1", "add [+0] 1"]);
+}
+
+#[test]
+fn dead_code_removes_comment_loops() {
+    let mut ir = parse("[a comment with commands, in it.] +[-] [skipped] [also skipped] .");
+    let mut pass = DeadCode::new();
+    pass.run(&mut ir);
+
+    assert_eq!(instructions(&ir), ["add [+0] 1", "loop", "put [+0]"]);
+    assert_eq!(pass.statistics()[0], ("loops removed", 3));
+    assert_eq!(pass.statistics()[1], ("instructions removed", 5));
+}
+
+#[test]
+fn dead_code_tracks_known_cells() {
+    // `>` reaches a cell that is still zero and `+-` keeps it zero,
+    // after a loop only the current cell is known and `,` forgets it
+    let mut ir = parse(">[.]+-[.]<+[-]>[.],[.]");
+    DeadCode::new().run(&mut ir);
+    assert_eq!(instructions(&ir), ["add [+0] 1", "loop", "right 1", "loop", "get [+0]", "loop"]);
+}
+
+#[test]
+fn dead_code_cancels_runs() {
+    let mut ir = parse(",+>><<-.<>+++--.");
+    let mut pass = DeadCode::new();
+    pass.run(&mut ir);
+
+    assert_eq!(instructions(&ir), ["get [+0]", "put [+0]", "add [+0] 1", "put [+0]"]);
+    assert_eq!(pass.statistics()[2], ("runs cancelled", 4));
+}
+
+#[test]
+fn dead_code_removes_redundant_stores() {
+    let mut ir = parse("[-]>[-]<,[-][-]");
+    PassManager::with_level(1).run(&mut ir);
+    assert_eq!(instructions(&ir), ["get [+0]", "set [+0] 0"]);
+}
+
+#[test]
+fn dead_code_wraps_around_the_tape() {
+    // on a tape of 4 cells `>>>>` is back on the cell `+` set, so `[-]` runs and clears it
+    let src = "+>>>>[-]<<<<[.]";
+    let mut ir = parse(src);
+    DeadCode::new().run(&mut ir);
+    assert_eq!(instructions(&ir), ["add [+0] 1", "loop"]);
+
+    let config = VMConfig { tape_size: 4, tape_policy: TapePolicy::Wrap, ..VMConfig::default() };
+    let mut ir = parse(src);
+    DeadCode::with_config(&config).run(&mut ir);
+    assert_eq!(instructions(&ir), ["add [+0] 1", "right 4", "loop", "left 4"]);
+}
+
+#[test]
+fn partial_eval_precomputes_output() {
+    let mut ir = ir::parse_bytes(&std::fs::read(concat!(env!("CARGO_MANIFEST_DIR"), "/example/hello.bf")).unwrap(), false)
+        .unwrap();
+    PartialEval::default().run(&mut ir);
+    assert_eq!(instructions(&ir), [r#"put bytes "Hello World!\n""#]);
+}
+
+#[test]
+fn partial_eval_stops_at_input() {
+    let mut ir = parse("++++++++[>++++++++<-]>+.>++<,.");
+    let mut pass = PartialEval::default();
+    pass.run(&mut ir);
+
+    assert_eq!(
+        instructions(&ir),
+        [r#"put bytes "A""#, "set [+1] 65", "set [+2] 2", "right 1", "get [+0]", "put [+0]"]
+    );
+    assert_eq!(pass.statistics()[0], ("instructions evaluated", 8));
+}
+
+#[test]
+fn partial_eval_respects_budget() {
+    // the loop never ends, everything before it is still evaluated
+    let mut ir = parse("+.+[>+<]");
+    PartialEval::new(1000, &VMConfig::default()).run(&mut ir);
+    assert_eq!(instructions(&ir), [r#"put bytes "\x01""#, "set [+0] 2", "loop"]);
+}